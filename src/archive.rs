@@ -0,0 +1,151 @@
+use chrono::{Local, NaiveDateTime, TimeZone};
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Separates an archive from the path of a member inside it, `archive.zip!/inner/path.dcm`
+pub const SEPARATOR: &str = "!/";
+
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+fn archive_kind(name: &str) -> Option<ArchiveKind> {
+    let name = name.to_lowercase();
+    if name.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(ArchiveKind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(ArchiveKind::TarGz)
+    } else {
+        None
+    }
+}
+
+/// Whether a file is a ZIP or (gzipped) TAR archive, by its name
+pub fn is_archive(name: &str) -> bool {
+    archive_kind(name).is_some()
+}
+
+/// Called with the virtual path, contents and modification time of each member
+type VisitMember<'a> = dyn FnMut(&str, &mut dyn Read, Option<SystemTime>) -> io::Result<()> + 'a;
+
+/// Called with the virtual path of a member that could not be read or visited (of the archive
+/// when its name is unknown) and the error
+type MemberError<'a> = dyn FnMut(&str, io::Error) + 'a;
+
+/// Virtual path of a member, `<archive>!/<member>`
+fn member_path(archive: &str, member: &str) -> String {
+    format!("{}{}{}", archive, SEPARATOR, member.trim_start_matches('/'))
+}
+
+/// Visit the file members of an archive, descending into nested archives.
+///
+/// `archive_path` is the virtual path of the archive (its own path, or `outer.zip!/inner.zip`
+/// when nested). Only members for which `wanted` returns true are read: a nested archive is
+/// opened, after copying it to a temporary file, and any other member is passed to `visit`
+/// with its virtual path and modification time.
+///
+/// A member that fails is passed to `failed` and the others are still visited; only an
+/// archive that cannot be opened at all is an error.
+pub fn visit_members(
+    archive: &Path,
+    archive_path: &str,
+    wanted: &dyn Fn(&str) -> bool,
+    visit: &mut VisitMember,
+    failed: &mut MemberError,
+) -> io::Result<()> {
+    let kind = archive_kind(archive_path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("not an archive: {}", archive_path),
+        )
+    })?;
+    // `failed` is passed along, as the loops below report to it too
+    let mut handle = |name: &str,
+                      reader: &mut dyn Read,
+                      modified: Option<SystemTime>,
+                      failed: &mut MemberError| {
+        let path = member_path(archive_path, name);
+        if !wanted(&path) {
+            return;
+        }
+        let result = if is_archive(name) {
+            tempfile::NamedTempFile::new().and_then(|mut nested| {
+                io::copy(reader, &mut nested)?;
+                visit_members(nested.path(), &path, wanted, visit, failed)
+            })
+        } else {
+            visit(&path, reader, modified)
+        };
+        if let Err(err) = result {
+            failed(&path, err);
+        }
+    };
+
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(File::open(archive)?)?;
+            for i in 0..zip.len() {
+                let mut member = match zip.by_index(i) {
+                    Ok(member) => member,
+                    Err(err) => {
+                        failed(archive_path, err.into());
+                        continue;
+                    }
+                };
+                if member.is_dir() {
+                    continue;
+                }
+                let name = member.name().to_string();
+                // ZIP times are local, without timezone
+                let modified = member
+                    .last_modified()
+                    .and_then(|time| NaiveDateTime::try_from(time).ok())
+                    .and_then(|time| Local.from_local_datetime(&time).single())
+                    .map(SystemTime::from);
+                handle(&name, &mut member, modified, failed);
+            }
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let file = File::open(archive)?;
+            let reader: Box<dyn Read> = match kind {
+                ArchiveKind::TarGz => Box::new(GzDecoder::new(file)),
+                _ => Box::new(file),
+            };
+            let mut tar = tar::Archive::new(reader);
+            // After a damaged header, the entries stop
+            for member in tar.entries()? {
+                let mut member = match member {
+                    Ok(member) => member,
+                    Err(err) => {
+                        failed(archive_path, err);
+                        continue;
+                    }
+                };
+                if !member.header().entry_type().is_file() {
+                    continue;
+                }
+                let name = match member.path() {
+                    Ok(name) => name.to_string_lossy().to_string(),
+                    Err(err) => {
+                        failed(archive_path, err);
+                        continue;
+                    }
+                };
+                let modified = member
+                    .header()
+                    .mtime()
+                    .ok()
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+                handle(&name, &mut member, modified, failed);
+            }
+        }
+    }
+    Ok(())
+}
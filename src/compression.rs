@@ -0,0 +1,60 @@
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// Compression of a single file, told by its extension (`.dcm.gz`, `.dcm.zst`)
+#[derive(Debug, Clone, Copy)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn of(path: &Path) -> Option<Self> {
+        let ext = path.extension()?;
+        if ext.eq_ignore_ascii_case("gz") {
+            Some(Compression::Gzip)
+        } else if ext.eq_ignore_ascii_case("zst") {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Reader of the decompressed contents, decompressing as it is read
+    pub fn decoder<'a>(self, reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::Gzip => Box::new(GzDecoder::new(reader)),
+            Compression::Zstd => Box::new(
+                ruzstd::decoding::StreamingDecoder::new(reader)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            ),
+        })
+    }
+}
+
+/// Path without the compression extension, `scan.dcm` for `scan.dcm.gz`
+pub fn uncompressed_path(path: &Path) -> PathBuf {
+    match Compression::of(path) {
+        Some(_) => path.with_extension(""),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Whether a file is a compressed DICOM file, `.dcm.gz` or `.dcm.zst`
+pub fn is_compressed_dicom(path: &Path) -> bool {
+    Compression::of(path).is_some()
+        && uncompressed_path(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("dcm"))
+}
+
+/// Reader of a file, decompressed if it is compressed
+pub fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    let file = BufReader::new(File::open(path)?);
+    match Compression::of(path) {
+        Some(compression) => compression.decoder(file),
+        None => Ok(Box::new(file)),
+    }
+}
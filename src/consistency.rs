@@ -0,0 +1,144 @@
+use duckdb::Connection;
+use serde::Serialize;
+use std::collections::HashSet;
+
+use crate::sql::{quote_ident, strptime_expr, DATE_FORMATS};
+
+/// One of the differing values of a patient's field, with where it was found.
+/// Values are normalised first, so that only real disagreements are reported.
+#[derive(Debug, Serialize)]
+pub struct PatientConflict {
+    pub patient_id: String,
+    pub field: String,
+    pub value: String,
+    pub source: String,
+    pub rows: usize,
+    /// One of the files holding this value, to start fixing the upstream record from
+    pub example_file_path: String,
+}
+
+fn column(name: &str) -> String {
+    format!("NULLIF(trim({}), '')", quote_ident(name))
+}
+
+/// Sex codes as written by DICOM (`M`/`F`/`O`) and crystal-eye (`Male`...); unknown is no value
+fn sex_expr() -> String {
+    format!(
+        "CASE upper({sex})
+             WHEN 'MALE' THEN 'M' WHEN '1' THEN 'M'
+             WHEN 'FEMALE' THEN 'F' WHEN '2' THEN 'F'
+             WHEN 'OTHER' THEN 'O'
+             WHEN 'U' THEN NULL WHEN 'UNKNOWN' THEN NULL
+             ELSE upper({sex})
+         END",
+        sex = column("sex")
+    )
+}
+
+/// DOB as an ISO date whatever the date format, the raw value if it cannot be parsed
+fn dob_expr() -> String {
+    format!(
+        "coalesce(strftime(CAST({} AS DATE), '%Y-%m-%d'), {})",
+        strptime_expr(&quote_ident("dob"), DATE_FORMATS),
+        column("dob")
+    )
+}
+
+/// Name as `FAMILY^GIVEN`, upper case without accents. Older CSVs only have `patient_name`,
+/// `family^given` for DICOM and `first last` for crystal-eye.
+fn name_expr(columns: &[String]) -> String {
+    let name = if columns.iter().any(|c| c == "patient_family_name") {
+        format!(
+            "CASE WHEN {family} IS NULL AND {given} IS NULL THEN NULL
+                  ELSE concat_ws('^', coalesce({family}, ''), coalesce({given}, '')) END",
+            family = column("patient_family_name"),
+            given = column("patient_given_name")
+        )
+    } else {
+        format!(
+            "CASE WHEN {name} IS NULL THEN NULL
+                  WHEN contains({name}, '^') THEN concat_ws('^', trim(split_part({name}, '^', 1)), trim(split_part({name}, '^', 2)))
+                  ELSE concat_ws('^', regexp_extract({name}, '(\\S+)$', 1), regexp_extract({name}, '^(\\S+)', 1)) END",
+            name = column("patient_name")
+        )
+    };
+    format!("NULLIF(upper(strip_accents({})), '^')", name)
+}
+
+/// Patients whose files disagree on sex, DOB or name, one row per differing value and source
+pub fn patient_conflicts(
+    conn: &Connection,
+    rows_view: &str,
+    columns: &[String],
+) -> Result<Vec<PatientConflict>, duckdb::Error> {
+    let has = |name: &str| columns.iter().any(|c| c == name);
+    let mut fields = Vec::new();
+    if has("sex") {
+        fields.push(("sex", sex_expr()));
+    }
+    if has("dob") {
+        fields.push(("dob", dob_expr()));
+    }
+    if has("patient_family_name") || has("patient_name") {
+        fields.push(("name", name_expr(columns)));
+    }
+    let source = if has("modality") {
+        "CASE WHEN modality = 'CE' THEN 'crystal-eye' ELSE 'DICOM' END"
+    } else {
+        "'DICOM'"
+    };
+    let file_path = if has("file_path") {
+        quote_ident("file_path")
+    } else {
+        "''".to_string()
+    };
+
+    let mut conflicts = Vec::new();
+    for (field, value) in fields {
+        let query = format!(
+            "WITH normalised AS (
+                 SELECT patient_id, {value} AS value, {source} AS source, {file_path} AS file_path
+                 FROM {rows}
+                 WHERE {patient_id} IS NOT NULL
+             ),
+             conflicting AS (
+                 SELECT patient_id FROM normalised
+                 WHERE value IS NOT NULL
+                 GROUP BY patient_id
+                 HAVING count(DISTINCT value) > 1
+             )
+             SELECT patient_id, value, source, count(*), coalesce(min(file_path), '')
+             FROM normalised JOIN conflicting USING (patient_id)
+             WHERE value IS NOT NULL
+             GROUP BY patient_id, value, source
+             ORDER BY patient_id, value, source",
+            rows = rows_view,
+            patient_id = column("patient_id"),
+        );
+        let mut stmt = conn.prepare(&query)?;
+        let rows = stmt.query_map([], |row| {
+            Ok(PatientConflict {
+                patient_id: row.get(0)?,
+                field: field.to_string(),
+                value: row.get(1)?,
+                source: row.get(2)?,
+                rows: row.get(3)?,
+                example_file_path: row.get(4)?,
+            })
+        })?;
+        for conflict in rows {
+            conflicts.push(conflict?);
+        }
+    }
+    conflicts.sort_by(|a, b| a.patient_id.cmp(&b.patient_id));
+    Ok(conflicts)
+}
+
+/// Number of distinct patients with at least one conflict
+pub fn conflicting_patients(conflicts: &[PatientConflict]) -> usize {
+    conflicts
+        .iter()
+        .map(|c| c.patient_id.as_str())
+        .collect::<HashSet<_>>()
+        .len()
+}
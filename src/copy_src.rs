@@ -1,18 +1,25 @@
 use chrono::{Days, NaiveDate};
 use clap::Parser;
 use duckdb::{AccessMode, Config, Connection, Error};
-use std::collections::HashSet;
-use std::fs::{self, OpenOptions};
-use std::io::Write;
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process;
 use tqdm::tqdm;
+mod archive;
+mod compression;
+mod exclusions;
 mod helpers;
-use helpers::handle_output_path;
+mod manufacturer;
+mod roots;
+use compression::Compression;
+use exclusions::PatientExclusions;
+use helpers::{handle_output_path, normalise_separators};
+use roots::Root;
 /// Command line arguments
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Copy DICOM files based on patient IDs", long_about = None)]
-
 struct Opt {
     /// Whether to overwrite existing files
     #[arg(short, long)]
@@ -29,6 +36,28 @@ struct Opt {
     /// Database file to use
     #[arg(short = 'd', long = "database", default_value = "open_sight.duckdb")]
     database: String,
+
+    /// File of opted-out patient IDs (plain or `sha256:<hex digest>`), never copied
+    #[arg(long)]
+    exclude_patients: Option<PathBuf>,
+
+    /// Only copy files of these image types (e.g. `oct,faf`), needs a database with `image_type`
+    #[arg(long, value_delimiter = ',')]
+    image_type: Vec<String>,
+
+    /// Decompress `.dcm.gz`/`.dcm.zst` files on copy, rather than copying them as they are
+    #[arg(long)]
+    decompress: bool,
+
+    /// Where a root of the index is on this machine, as `LABEL=PATH` (repeatable): its files
+    /// are found from their `relative_path`, needs a database with `root_label`
+    #[arg(long = "root", value_parser = roots::parse_root)]
+    roots: Vec<Root>,
+
+    /// Rewrite indexed paths starting with a prefix, as `PREFIX=>REPLACEMENT` (repeatable, the
+    /// first matching rule is used), e.g. `\\server\share=>/mnt/share`
+    #[arg(long = "path-map", value_parser = parse_path_map)]
+    path_maps: Vec<PathMap>,
 }
 
 fn read_patient_ids(file_path: &str) -> Result<Vec<String>, std::io::Error> {
@@ -39,34 +68,169 @@ fn read_patient_ids(file_path: &str) -> Result<Vec<String>, std::io::Error> {
         .collect())
 }
 
+/// `--path-map` rule, replacing a prefix of the indexed paths
+#[derive(Debug, Clone)]
+struct PathMap {
+    /// With `/` separators, matched ignoring case
+    prefix: String,
+    replacement: String,
+}
+
+/// `PREFIX=>REPLACEMENT` of `--path-map`
+fn parse_path_map(value: &str) -> Result<PathMap, String> {
+    match value.split_once("=>") {
+        Some((prefix, replacement)) if !prefix.is_empty() => {
+            let prefix = normalise_separators(prefix);
+            Ok(PathMap {
+                prefix: match prefix.trim_end_matches('/') {
+                    "" => prefix.clone(),
+                    trimmed => trimmed.to_string(),
+                },
+                replacement: replacement.to_string(),
+            })
+        }
+        _ => Err(format!("expected PREFIX=>REPLACEMENT, got {}", value)),
+    }
+}
+
+impl PathMap {
+    /// Path with the prefix replaced and `/` separators, `None` if it does not start with
+    /// the prefix (as whole folder names)
+    fn apply(&self, file_path: &str) -> Option<String> {
+        let path = normalise_separators(file_path);
+        let head = path.get(..self.prefix.len())?;
+        let rest = &path[self.prefix.len()..];
+        if !head.eq_ignore_ascii_case(&self.prefix)
+            || !(rest.is_empty() || rest.starts_with('/') || self.prefix.ends_with('/'))
+        {
+            return None;
+        }
+        let replacement = normalise_separators(&self.replacement);
+        Some(match rest.trim_start_matches('/') {
+            "" => replacement,
+            rest => format!("{}/{}", replacement.trim_end_matches('/'), rest),
+        })
+    }
+}
+
+/// Where the files of the index are on this machine
+struct SourcePaths {
+    roots: Vec<Root>,
+    path_maps: Vec<PathMap>,
+    /// Paths no `--path-map` rule matched, reported at the end
+    unmapped: BTreeSet<String>,
+}
+
+impl SourcePaths {
+    /// Path of a file: below the root of its label if mapped, else `file_path` rewritten by
+    /// the first `--path-map` rule matching it, else `file_path` as it is
+    fn resolve(&mut self, file_path: &str, root_label: &str, relative_path: &str) -> String {
+        if let Some(path) = self.below_root(root_label, relative_path) {
+            return path;
+        }
+        if self.path_maps.is_empty() {
+            return file_path.to_string();
+        }
+        match self.path_maps.iter().find_map(|map| map.apply(file_path)) {
+            Some(path) => path,
+            None => {
+                self.unmapped.insert(file_path.to_string());
+                file_path.to_string()
+            }
+        }
+    }
+
+    fn below_root(&self, root_label: &str, relative_path: &str) -> Option<String> {
+        let root = self
+            .roots
+            .iter()
+            .find(|root| !root_label.is_empty() && root.label == root_label)?;
+        // The archive member part keeps its `/` separators
+        let (relative_file, member) = match relative_path.split_once(archive::SEPARATOR) {
+            Some((relative_file, member)) => (relative_file, Some(member)),
+            None => (relative_path, None),
+        };
+        let path = relative_file
+            .split('/')
+            .fold(root.path.clone(), |path, component| path.join(component));
+        Some(match member {
+            Some(member) => format!("{}{}{}", path.display(), archive::SEPARATOR, member),
+            None => path.display().to_string(),
+        })
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn copy_files(
     patient_id: &str,
     output_directory: &str,
     overwrite: bool,
     conn: &Connection,
+    exclusions: Option<&PatientExclusions>,
+    image_types: &[String],
+    decompress: bool,
+    source_paths: &mut SourcePaths,
 ) -> Result<bool, Error> {
-    let query = format!( "SELECT * FROM main.open_sight WHERE patient_id = '{}' AND modality IN ('OP','OPT') AND manufacturer = 'Heidelberg Engineering' ORDER BY patient_id, scan_date, laterality, modality", patient_id );
-    // 0          1            2          3   4   5         6        7            8                  9        10        11
-    // patient_id,patient_name,laterality,sex,dob,scan_date,modality,manufacturer,series_description,modified,file_size,file_path
+    let image_type_filter = if image_types.is_empty() {
+        String::new()
+    } else {
+        let quoted: Vec<String> = image_types
+            .iter()
+            .map(|t| format!("'{}'", t.trim().replace('\'', "''")))
+            .collect();
+        format!(" AND image_type IN ({})", quoted.join(","))
+    };
+    let root_columns = if source_paths.roots.is_empty() {
+        "'', ''"
+    } else {
+        "COALESCE(root_label, ''), COALESCE(relative_path, '')"
+    };
+    // Columns are selected by name, the table may have more of them depending on the CSV ingested
+    let query = format!( "SELECT laterality, scan_date, modality, file_path, manufacturer, {} FROM main.open_sight WHERE patient_id = '{}' AND modality IN ('OP','OPT'){} ORDER BY patient_id, scan_date, laterality, modality", root_columns, patient_id, image_type_filter );
 
     let mut stmt = conn.prepare(&query)?;
     let rows: Vec<_> = stmt
         .query_map([], |row| {
             Ok((
-                row.get::<_, String>(2)?,  // laterality
-                row.get::<_, u64>(5)?,     // scan_date
-                row.get::<_, String>(6)?,  // modality
-                row.get::<_, String>(11)?, // file_path
+                row.get::<_, String>(0)?, // laterality
+                row.get::<_, u64>(1)?,    // scan_date
+                row.get::<_, String>(2)?, // modality
+                row.get::<_, String>(3)?, // file_path
+                row.get::<_, String>(4)?, // manufacturer
+                row.get::<_, String>(5)?, // root_label
+                row.get::<_, String>(6)?, // relative_path
             ))
         })?
         .filter_map(|result| result.ok())
+        // Normalised, so that e.g. `HEIDELBERG ENGINEERING GmbH` matches too
+        .filter(|row| manufacturer::normalise(&row.4) == manufacturer::HEIDELBERG_ENGINEERING)
         .collect();
 
+    // Refuse to copy anything for opted-out patients, only count the records; checked first,
+    // so that an opted-out ID missing from the database is not reported as not found either
+    if let Some(exclusions) = exclusions {
+        if exclusions.contains(patient_id) {
+            exclusions.record_excluded(rows.len());
+            return Ok(true);
+        }
+    }
+
     // If rows are empty, the patient ID was not found in the database
     if rows.is_empty() {
         return Ok(false);
     }
 
+    // Resolved only now, so that no path of an opted-out patient is reported as unmapped
+    let rows: Vec<_> = rows
+        .into_iter()
+        .map(
+            |(laterality, scan_date, modality, file_path, _, root_label, relative_path)| {
+                let file_path = source_paths.resolve(&file_path, &root_label, &relative_path);
+                (laterality, scan_date, modality, file_path)
+            },
+        )
+        .collect();
+
     let mut missing_files = HashSet::new();
 
     for (laterality, scan_date_days, modality, file_path) in tqdm(rows) {
@@ -74,11 +238,13 @@ fn copy_files(
             .unwrap()
             .checked_add_days(Days::new(scan_date_days))
             .unwrap();
-        let file_name = Path::new(&file_path)
-            .file_name()
-            .unwrap()
-            .to_string_lossy()
-            .to_string();
+        let decompress = decompress && compression::is_compressed_dicom(Path::new(&file_path));
+        let file_name = if decompress {
+            compression::uncompressed_path(Path::new(&file_path))
+        } else {
+            PathBuf::from(&file_path)
+        };
+        let file_name = file_name.file_name().unwrap().to_string_lossy().to_string();
         let output_file_name = format!("{}_{}", modality, file_name);
         let formatted_date = scan_date.format("%Y%m%d").to_string();
 
@@ -93,7 +259,7 @@ fn copy_files(
 
         let output_file_path = patient_folder.join(output_file_name);
         if (!output_file_path.exists() || overwrite)
-            && fs::copy(&file_path, &output_file_path).is_err()
+            && copy_file(&file_path, &output_file_path, decompress).is_err()
         {
             missing_files.insert(file_path);
         }
@@ -102,6 +268,56 @@ fn copy_files(
     Ok(missing_files.is_empty())
 }
 
+/// Copy a file, extracting it when it is an archive member (`archive.zip!/inner/path.dcm`)
+/// and decompressing it if asked
+fn copy_file(file_path: &str, output_file_path: &Path, decompress: bool) -> io::Result<()> {
+    let write = |reader: &mut dyn Read| -> io::Result<()> {
+        let mut output = File::create(output_file_path)?;
+        match Compression::of(Path::new(file_path)).filter(|_| decompress) {
+            Some(compression) => io::copy(&mut compression.decoder(reader)?, &mut output)?,
+            None => io::copy(reader, &mut output)?,
+        };
+        Ok(())
+    };
+    let Some((archive_file, _)) = file_path.split_once(archive::SEPARATOR) else {
+        if decompress {
+            let mut reader = compression::open(Path::new(file_path))?;
+            io::copy(&mut reader, &mut File::create(output_file_path)?)?;
+            return Ok(());
+        }
+        return fs::copy(file_path, output_file_path).map(|_| ());
+    };
+    let mut found = false;
+    let mut member_error = None;
+    archive::visit_members(
+        Path::new(archive_file),
+        archive_file,
+        // Only the member and the nested archives leading to it
+        &|member| {
+            member == file_path
+                || file_path.starts_with(&format!("{}{}", member, archive::SEPARATOR))
+        },
+        &mut |_, reader, _| {
+            write(reader)?;
+            found = true;
+            Ok(())
+        },
+        &mut |_, err| {
+            member_error.get_or_insert(err);
+        },
+    )?;
+    if found {
+        Ok(())
+    } else if let Some(err) = member_error {
+        Err(err)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} not found", file_path),
+        ))
+    }
+}
+
 fn main() {
     let args = Opt::parse();
     let patient_ids = read_patient_ids(&args.patient_id_file).unwrap_or_else(|err| {
@@ -120,9 +336,33 @@ fn main() {
         process::exit(1);
     });
 
+    let exclusions = args.exclude_patients.as_ref().map(|path| {
+        let exclusions = PatientExclusions::load(path).unwrap_or_else(|err| {
+            eprintln!("Error reading patient opt-out file: {}", err);
+            process::exit(1);
+        });
+        println!("Loaded {} patient opt-outs", exclusions.entries());
+        exclusions
+    });
+
+    let mut source_paths = SourcePaths {
+        roots: args.roots,
+        path_maps: args.path_maps,
+        unmapped: BTreeSet::new(),
+    };
+
     let mut not_found_patients = Vec::new();
     for patient_id in tqdm(&patient_ids) {
-        match copy_files(patient_id, &args.output_directory, args.overwrite, &conn) {
+        match copy_files(
+            patient_id,
+            &args.output_directory,
+            args.overwrite,
+            &conn,
+            exclusions.as_ref(),
+            &args.image_type,
+            args.decompress,
+            &mut source_paths,
+        ) {
             Ok(false) => not_found_patients.push(patient_id.clone()),
             Err(e) => {
                 eprintln!("Error processing patient {}: {}", patient_id, e);
@@ -132,32 +372,57 @@ fn main() {
         }
     }
 
+    if let Some(exclusions) = &exclusions {
+        println!(
+            "Records of opted-out patients not copied: {}",
+            exclusions.excluded()
+        );
+    }
+
     if !not_found_patients.is_empty() {
         let output_path = PathBuf::from("patient_ids_not_found.csv");
-
-        // Handle the output file path, pass `true` for overwriting the file
-        let _ = handle_output_path(&output_path, args.overwrite);
-
-        // Open the file in write mode, handling the Result
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&output_path)
-            .unwrap_or_else(|err| {
-                eprintln!("Error opening output file: {}", err);
-                process::exit(1);
-            });
-
-        // Write the patient IDs to the file
-        for patient_id in not_found_patients {
-            writeln!(file, "{}", patient_id).unwrap_or_else(|err| {
-                eprintln!("Error writing to output file: {}", err);
-                process::exit(1);
-            });
-        }
+        write_lines(&output_path, &not_found_patients, args.overwrite);
         println!(
             "Patient IDs not found in DB, see file: {}",
             output_path.display()
         );
     }
+
+    if !source_paths.unmapped.is_empty() {
+        let output_path = PathBuf::from("paths_not_mapped.csv");
+        write_lines(&output_path, &source_paths.unmapped, args.overwrite);
+        println!(
+            "{} paths matched no --path-map rule, see file: {}",
+            source_paths.unmapped.len(),
+            output_path.display()
+        );
+    }
+}
+
+/// Write one value per line, moving an existing file aside unless overwriting
+fn write_lines<'a>(
+    output_path: &PathBuf,
+    lines: impl IntoIterator<Item = &'a String>,
+    overwrite: bool,
+) {
+    // Handle the output file path, pass `true` for overwriting the file
+    let _ = handle_output_path(output_path, overwrite);
+
+    // Open the file in write mode, handling the Result
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output_path)
+        .unwrap_or_else(|err| {
+            eprintln!("Error opening output file: {}", err);
+            process::exit(1);
+        });
+
+    for line in lines {
+        writeln!(file, "{}", line).unwrap_or_else(|err| {
+            eprintln!("Error writing to output file: {}", err);
+            process::exit(1);
+        });
+    }
 }
@@ -0,0 +1,241 @@
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat, Utc};
+use clap::ValueEnum;
+use std::io;
+use std::time::SystemTime;
+
+use crate::placeholder_dates::PLACEHOLDER_DATES;
+
+/// How dates are written to the CSV
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DateFormat {
+    /// ISO 8601: `YYYY-mm-dd` dates, `YYYY-mm-ddTHH:MM:SSZ` UTC timestamps
+    Iso,
+    /// Legacy `dd-mm-YYYY` dates, `dd-mm-YYYY HH:MM:SS` local timestamps
+    Legacy,
+}
+
+const ISO_DATE: &str = "%Y-%m-%d";
+const LEGACY_DATE: &str = "%d-%m-%Y";
+
+impl DateFormat {
+    fn date_pattern(self) -> &'static str {
+        match self {
+            DateFormat::Iso => ISO_DATE,
+            DateFormat::Legacy => LEGACY_DATE,
+        }
+    }
+
+    /// Format of a date or timestamp previously written by open-sight, if recognisable
+    pub fn detect(value: &str) -> Option<DateFormat> {
+        let date = value.get(..10)?;
+        if NaiveDate::parse_from_str(date, ISO_DATE).is_ok() {
+            Some(DateFormat::Iso)
+        } else if NaiveDate::parse_from_str(date, LEGACY_DATE).is_ok() {
+            Some(DateFormat::Legacy)
+        } else {
+            None
+        }
+    }
+
+    pub fn format(self, date: NaiveDate) -> String {
+        date.format(self.date_pattern()).to_string()
+    }
+
+    /// Scan timestamps are written as recorded by the device, without timezone
+    pub fn format_datetime(self, datetime: NaiveDateTime) -> String {
+        match self {
+            DateFormat::Iso => datetime.format("%Y-%m-%dT%H:%M:%S%.f").to_string(),
+            DateFormat::Legacy => datetime.format("%d-%m-%Y %H:%M:%S%.f").to_string(),
+        }
+    }
+}
+
+/// Parse a DICOM TM value (`HH[MM[SS[.FFFFFF]]]`, or the old `HH:MM:SS` form)
+pub fn parse_dicom_time(time_str: &str) -> Option<NaiveTime> {
+    let time_str = time_str.trim().replace(':', "");
+    let (hms, fraction) = match time_str.split_once('.') {
+        Some((hms, fraction)) => (hms, fraction),
+        None => (time_str.as_str(), ""),
+    };
+    if hms.len() < 2
+        || hms.len() % 2 != 0
+        || hms.len() > 6
+        || !hms.bytes().all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    let field = |i: usize| hms.get(i..i + 2).map_or(Some(0), |v| v.parse::<u32>().ok());
+    let micros = if fraction.is_empty() {
+        0
+    } else {
+        let digits: String = fraction.chars().take(6).collect();
+        digits.parse::<u32>().ok()? * 10u32.pow(6 - digits.len() as u32)
+    };
+    NaiveTime::from_hms_micro_opt(field(0)?, field(2)?, field(4)?, micros)
+}
+
+/// Combine a DICOM DA value with an optional TM value, midnight if the time is missing
+pub fn parse_dicom_date_time(date_str: &str, time_str: &str) -> Option<NaiveDateTime> {
+    let date = NaiveDate::parse_from_str(date_str.trim(), "%Y%m%d").ok()?;
+    let time = parse_dicom_time(time_str).unwrap_or(NaiveTime::MIN);
+    Some(date.and_time(time))
+}
+
+/// Parse a DICOM DT value (`YYYYMMDD[HHMMSS[.FFFFFF]][&ZZXX]`), ignoring the UTC offset
+pub fn parse_dicom_datetime(datetime_str: &str) -> Option<NaiveDateTime> {
+    let datetime_str = datetime_str.trim();
+    let local = datetime_str
+        .find(['+', '-'])
+        .map_or(datetime_str, |i| &datetime_str[..i]);
+    let (date, time) = local.split_at(local.len().min(8));
+    parse_dicom_date_time(date, time)
+}
+
+/// Oldest plausible age at scan, in years
+const MAX_AGE: f64 = 120.0;
+
+fn is_placeholder(date: NaiveDate) -> bool {
+    PLACEHOLDER_DATES
+        .iter()
+        .any(|placeholder| NaiveDate::parse_from_str(placeholder, ISO_DATE) == Ok(date))
+}
+
+/// Fractional age in years between two dates
+pub fn age_in_years(dob: NaiveDate, at: NaiveDate) -> f64 {
+    (at - dob).num_days() as f64 / 365.2425
+}
+
+/// Age at scan in years (2 decimals, empty if either date is missing) and `;`-separated flags
+/// for doubtful dates: `placeholder_dob`, `placeholder_scan_date`, `dob_two_digit_year` (century
+/// guessed), `dob_in_future`, `dob_after_scan`, `age_over_120` and `scan_in_future`.
+/// Dates are as written by open-sight, `today` is the date of the crawl.
+pub fn age_and_date_flags(
+    dob: &str,
+    scan_date: &str,
+    dob_two_digit_year: bool,
+    today: NaiveDate,
+) -> (String, String) {
+    let dob = parse_date(dob);
+    let scan = parse_date(scan_date);
+    let mut flags = Vec::new();
+
+    if dob.is_some_and(is_placeholder) {
+        flags.push("placeholder_dob");
+    }
+    if scan.is_some_and(is_placeholder) {
+        flags.push("placeholder_scan_date");
+    }
+    if dob.is_some() && dob_two_digit_year {
+        flags.push("dob_two_digit_year");
+    }
+    if dob.is_some_and(|dob| dob > today) {
+        flags.push("dob_in_future");
+    }
+    if scan.is_some_and(|scan| scan > today) {
+        flags.push("scan_in_future");
+    }
+
+    let age = match (dob, scan) {
+        (Some(dob), Some(scan)) => {
+            let age = age_in_years(dob, scan);
+            if dob > scan {
+                flags.push("dob_after_scan");
+            } else if age > MAX_AGE {
+                flags.push("age_over_120");
+            }
+            format!("{:.2}", age)
+        }
+        _ => String::new(),
+    };
+
+    (age, flags.join(";"))
+}
+
+/// Date formats of sidecars, file names and folder names, besides the DICOM one
+const LOOSE_DATE_FORMATS: &[&str] = &[
+    "%Y%m%d", "%Y-%m-%d", "%Y:%m:%d", "%d.%m.%Y", "%d-%m-%Y", "%d/%m/%Y",
+];
+const LOOSE_DATETIME_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y:%m:%d %H:%M:%S",
+    "%Y%m%d%H%M%S",
+    "%Y%m%d_%H%M%S",
+    "%d.%m.%Y %H:%M:%S",
+    "%d/%m/%Y %H:%M:%S",
+];
+
+/// Date of a sidecar, file name, folder name or EXIF value, in the usual formats
+pub fn parse_loose_date(value: &str) -> Option<NaiveDate> {
+    parse_loose_datetime(value)
+        .map(|datetime| datetime.date())
+        .or_else(|| {
+            LOOSE_DATE_FORMATS
+                .iter()
+                .find_map(|format| NaiveDate::parse_from_str(value.trim(), format).ok())
+        })
+}
+
+/// Date and time of a sidecar, file name, folder name or EXIF value, `None` for a date alone
+pub fn parse_loose_datetime(value: &str) -> Option<NaiveDateTime> {
+    LOOSE_DATETIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value.trim(), format).ok())
+}
+
+/// Parse a date written by open-sight, in either ISO or legacy format
+pub fn parse_date(date_str: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date_str, ISO_DATE)
+        .or_else(|_| NaiveDate::parse_from_str(date_str, LEGACY_DATE))
+        .ok()
+}
+
+pub fn format_modified_datetime(
+    modified: io::Result<SystemTime>,
+    date_format: DateFormat,
+) -> String {
+    match modified {
+        Ok(time) => match date_format {
+            DateFormat::Iso => {
+                let datetime: DateTime<Utc> = time.into();
+                datetime.to_rfc3339_opts(SecondsFormat::Secs, true)
+            }
+            DateFormat::Legacy => {
+                // Convert SystemTime to DateTime<Local>
+                let datetime: DateTime<Local> = time.into();
+                datetime.format("%d-%m-%Y %H:%M:%S").to_string()
+            }
+        },
+        Err(_) => {
+            // Return an empty string if there's an error
+            "".to_string()
+        }
+    }
+}
+
+pub fn format_date(date_str: &str, format_str: Option<&str>, date_format: DateFormat) -> String {
+    let default_format = "%Y%m%d";
+    let format_to_use = format_str.unwrap_or(default_format);
+
+    if let Ok(parsed_date) = NaiveDate::parse_from_str(date_str, format_to_use) {
+        date_format.format(parsed_date)
+    } else if format_str.is_none() {
+        // Attempt to handle ambiguous dates
+        attempt_ambiguous_date_parse(date_str, date_format)
+    } else {
+        String::new()
+    }
+}
+
+fn attempt_ambiguous_date_parse(date_str: &str, date_format: DateFormat) -> String {
+    // Try parsing assuming no century (e.g., "010180" becomes "1980-01-01")
+    if let Ok(parsed_date) = NaiveDate::parse_from_str(date_str, "%y%m%d") {
+        // Check if the parsed year is within a reasonable range
+        if parsed_date.year() > 1900 {
+            // Adjust threshold as needed
+            return date_format.format(parsed_date);
+        }
+    }
+
+    String::new() // Return empty if still unsuccessful
+}
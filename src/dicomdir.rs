@@ -0,0 +1,118 @@
+use dicom_dictionary_std::tags;
+use dicom_object::OpenFileOptions;
+use std::path::{Path, PathBuf};
+
+use crate::element_str;
+use crate::logging;
+
+/// Instance referenced by a DICOMDIR, with the patient, study and series records above it
+#[derive(Debug, Default, Clone)]
+pub struct DicomdirRecord {
+    pub path: PathBuf,
+    pub patient_id: String,
+    pub study_instance_uid: String,
+    pub series_instance_uid: String,
+    pub modality: String,
+    pub sop_instance_uid: String,
+}
+
+/// Whether a file is a DICOMDIR, by its name
+pub fn is_dicomdir(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.eq_ignore_ascii_case("DICOMDIR"))
+}
+
+/// Instances referenced by a DICOMDIR.
+///
+/// Directory records are read in the order they are stored, patient, study and series
+/// records each preceding the records below them, so the current ones are kept while
+/// walking through. Referenced File IDs are relative to the folder of the DICOMDIR.
+pub fn read_records(path: &Path) -> Result<Vec<DicomdirRecord>, Box<dyn std::error::Error>> {
+    let obj = OpenFileOptions::new().open_file(path)?;
+    let root = path.parent().unwrap_or(Path::new(""));
+    let Some(items) = obj
+        .element_opt(tags::DIRECTORY_RECORD_SEQUENCE)?
+        .and_then(|elem| elem.items())
+    else {
+        return Ok(Vec::new());
+    };
+
+    let mut current = DicomdirRecord::default();
+    let mut records = Vec::new();
+    for item in items {
+        match element_str(item, tags::DIRECTORY_RECORD_TYPE)?.trim() {
+            "PATIENT" => {
+                current = DicomdirRecord {
+                    patient_id: element_str(item, tags::PATIENT_ID)?,
+                    ..Default::default()
+                }
+            }
+            "STUDY" => {
+                current.study_instance_uid = element_str(item, tags::STUDY_INSTANCE_UID)?;
+                current.series_instance_uid.clear();
+                current.modality.clear();
+            }
+            "SERIES" => {
+                current.series_instance_uid = element_str(item, tags::SERIES_INSTANCE_UID)?;
+                current.modality = element_str(item, tags::MODALITY)?;
+            }
+            _ => {}
+        }
+        let file_id = element_str(item, tags::REFERENCED_FILE_ID)?;
+        if file_id.trim().is_empty() {
+            continue;
+        }
+        let path = file_id
+            .split('\\')
+            .map(str::trim)
+            .fold(root.to_path_buf(), |path, component| path.join(component));
+        records.push(DicomdirRecord {
+            path,
+            sop_instance_uid: element_str(item, tags::REFERENCED_SOP_INSTANCE_UID_IN_FILE)?,
+            ..current.clone()
+        });
+    }
+    Ok(records)
+}
+
+/// Attributes of a DICOMDIR record differing from the header of the file it references,
+/// as `attribute: record value != header value`, or only the attribute when logs are redacted
+/// since the values identify the patient. Empty record values are not compared.
+pub fn check_record(record: &DicomdirRecord) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let obj = OpenFileOptions::new()
+        .read_until(tags::PIXEL_DATA)
+        .open_file(&record.path)?;
+    let header = |tag| element_str(&obj, tag);
+    let checks: [(&str, &String, String); 5] = [
+        ("PatientID", &record.patient_id, header(tags::PATIENT_ID)?),
+        (
+            "StudyInstanceUID",
+            &record.study_instance_uid,
+            header(tags::STUDY_INSTANCE_UID)?,
+        ),
+        (
+            "SeriesInstanceUID",
+            &record.series_instance_uid,
+            header(tags::SERIES_INSTANCE_UID)?,
+        ),
+        ("Modality", &record.modality, header(tags::MODALITY)?),
+        (
+            "SOPInstanceUID",
+            &record.sop_instance_uid,
+            header(tags::SOP_INSTANCE_UID)?,
+        ),
+    ];
+    Ok(checks
+        .into_iter()
+        .filter(|(_, recorded, actual)| {
+            !recorded.trim().is_empty() && recorded.trim() != actual.trim()
+        })
+        .map(|(name, recorded, actual)| {
+            if logging::redacting() {
+                name.to_string()
+            } else {
+                format!("{}: {} != {}", name, recorded, actual)
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,63 @@
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use crate::helpers::sha256_hex;
+
+/// Prefix marking an entry of the opt-out file as the SHA-256 hex digest of a patient ID
+const HASH_PREFIX: &str = "sha256:";
+
+/// Patients who opted out, given either as plain IDs or as `sha256:<hex digest of ID>`
+pub struct PatientExclusions {
+    ids: HashSet<String>,
+    hashes: HashSet<String>,
+    excluded: Cell<usize>,
+}
+
+impl PatientExclusions {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let mut ids = HashSet::new();
+        let mut hashes = HashSet::new();
+        for line in contents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            match line.strip_prefix(HASH_PREFIX) {
+                Some(hash) => hashes.insert(hash.to_ascii_lowercase()),
+                None => ids.insert(line.to_string()),
+            };
+        }
+        Ok(PatientExclusions {
+            ids,
+            hashes,
+            excluded: Cell::new(0),
+        })
+    }
+
+    /// Number of entries in the opt-out file
+    pub fn entries(&self) -> usize {
+        self.ids.len() + self.hashes.len()
+    }
+
+    pub fn contains(&self, patient_id: &str) -> bool {
+        let patient_id = patient_id.trim();
+        if patient_id.is_empty() {
+            return false;
+        }
+        if self.ids.contains(patient_id) {
+            return true;
+        }
+        if self.hashes.is_empty() {
+            return false;
+        }
+        self.hashes.contains(&sha256_hex(patient_id.as_bytes()))
+    }
+
+    /// Count records left out because of the opt-out list
+    pub fn record_excluded(&self, count: usize) {
+        self.excluded.set(self.excluded.get() + count);
+    }
+
+    pub fn excluded(&self) -> usize {
+        self.excluded.get()
+    }
+}
@@ -1,7 +1,23 @@
+use sha2::{Digest, Sha256};
 use std::fs;
 use std::fs::remove_file;
 use std::path::PathBuf;
 
+/// Path with `/` separators, as indexes built on Windows have `\\server\share\...` paths
+pub fn normalise_separators(path: &str) -> String {
+    path.replace('\\', "/")
+}
+
+/// Lower-case hex encoding of bytes
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hex digest of the SHA-256 of the data, as `sha256()` in DuckDB
+pub fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
 pub fn handle_output_path(output_path: &PathBuf, overwrite: bool) -> std::io::Result<()> {
     // Check if the CSV file exists and rename it if necessary
     if output_path.exists() {
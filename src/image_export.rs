@@ -0,0 +1,290 @@
+use log::warn;
+use quick_xml::events::{BytesStart, Event};
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::logging;
+
+/// Image formats of non-DICOM exports, by extension, with their `source_format`
+const IMAGE_FORMATS: &[(&str, &str)] = &[
+    ("jpg", "jpeg"),
+    ("jpeg", "jpeg"),
+    ("png", "png"),
+    ("tif", "tiff"),
+    ("tiff", "tiff"),
+];
+
+/// Fields read from sidecars and file names, with the keys naming them (lower case, letters
+/// and digits only). A key is matched alone or prefixed by its parent XML element or INI
+/// section, e.g. `<Patient><ID>` as `patientid`.
+const FIELD_KEYS: &[(&str, &[&str])] = &[
+    (
+        "patient_id",
+        &["patientid", "patientkey", "mrn", "hospitalnumber"],
+    ),
+    ("patient_name", &["patientname"]),
+    (
+        "first_name",
+        &["firstname", "givenname", "patientfirstname"],
+    ),
+    (
+        "last_name",
+        &["lastname", "familyname", "surname", "patientlastname"],
+    ),
+    (
+        "dob",
+        &["dob", "birthdate", "dateofbirth", "patientbirthdate"],
+    ),
+    ("sex", &["sex", "gender", "patientsex"]),
+    (
+        "laterality",
+        &["laterality", "eye", "imagelaterality", "side"],
+    ),
+    (
+        "scan_date",
+        &[
+            "date",
+            "datetime",
+            "examdate",
+            "scandate",
+            "acquisitiondate",
+            "acquisitiondatetime",
+            "studydate",
+            "capturedate",
+        ],
+    ),
+    ("modality", &["modality"]),
+    ("manufacturer", &["manufacturer", "make"]),
+    ("model", &["model", "devicemodel", "manufacturermodelname"]),
+    ("serial_number", &["serialnumber", "deviceserialnumber"]),
+    (
+        "software",
+        &["software", "softwareversion", "softwareversions"],
+    ),
+    (
+        "description",
+        &["description", "seriesdescription", "protocol"],
+    ),
+];
+
+/// `source_format` of an image export (`jpeg`, `png` or `tiff`), `None` for other files
+pub fn source_format(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?;
+    IMAGE_FORMATS
+        .iter()
+        .find(|(image_ext, _)| ext.eq_ignore_ascii_case(image_ext))
+        .map(|(_, format)| *format)
+}
+
+/// Metadata of an image export, each field with where it came from
+#[derive(Debug, Default)]
+pub struct ExportMetadata {
+    fields: HashMap<&'static str, (String, &'static str)>,
+}
+
+impl ExportMetadata {
+    /// Value of a field, empty when no source gives it
+    pub fn value(&self, field: &str) -> &str {
+        self.fields.get(field).map_or("", |(value, _)| value)
+    }
+
+    /// Source of a field: `sidecar`, `file name` or the EXIF tag
+    pub fn source(&self, field: &str) -> &'static str {
+        self.fields.get(field).map_or("", |(_, source)| source)
+    }
+
+    /// Set a field unless an earlier source gave it
+    fn insert(&mut self, field: &'static str, value: &str, source: &'static str) {
+        let value = value.trim().trim_matches('\0').trim();
+        if !value.is_empty() {
+            self.fields
+                .entry(field)
+                .or_insert_with(|| (value.to_string(), source));
+        }
+    }
+
+    fn insert_keyed(&mut self, keys: &[(String, String)], source: &'static str) {
+        for (field, names) in FIELD_KEYS {
+            if let Some((_, value)) = keys.iter().find(|(key, _)| names.contains(&key.as_str())) {
+                self.insert(field, value, source);
+            }
+        }
+    }
+}
+
+/// Metadata of an image export, from its sidecar first, then its file name, then its EXIF/TIFF tags
+pub fn read_metadata(
+    path: &Path,
+    filename_pattern: Option<&Regex>,
+) -> Result<ExportMetadata, Box<dyn std::error::Error>> {
+    let mut metadata = ExportMetadata::default();
+    if let Some(sidecar) = sidecar_path(path) {
+        // Sidecars of some devices are in a legacy encoding rather than UTF-8
+        let contents = String::from_utf8_lossy(&fs::read(&sidecar)?).to_string();
+        let keys = if sidecar_is_xml(&sidecar) {
+            xml_keys(&contents)?
+        } else {
+            ini_keys(&contents)
+        };
+        metadata.insert_keyed(&keys, "sidecar");
+    }
+    if let (Some(pattern), Some(name)) = (filename_pattern, path.file_name()) {
+        if let Some(captures) = pattern.captures(&name.to_string_lossy()) {
+            for (field, _) in FIELD_KEYS {
+                if let Some(value) = captures.name(field) {
+                    metadata.insert(field, value.as_str(), "file name");
+                }
+            }
+        }
+    }
+    read_exif(path, &mut metadata)?;
+    Ok(metadata)
+}
+
+/// `image.xml` or `image.ini` next to `image.jpg`
+fn sidecar_path(path: &Path) -> Option<PathBuf> {
+    ["xml", "XML", "ini", "INI"]
+        .iter()
+        .map(|ext| path.with_extension(ext))
+        .find(|sidecar| sidecar.is_file())
+}
+
+fn sidecar_is_xml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("xml"))
+}
+
+fn normalise_key(key: &str) -> String {
+    key.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Keys of a value, alone and prefixed by its parent element or section
+fn push_keys(keys: &mut Vec<(String, String)>, parent: &str, name: &str, value: &str) {
+    keys.push((
+        normalise_key(&format!("{}{}", parent, name)),
+        value.to_string(),
+    ));
+    keys.push((normalise_key(name), value.to_string()));
+}
+
+/// Keys of the attributes of an element, returning its name
+fn attribute_keys(
+    keys: &mut Vec<(String, String)>,
+    element: &BytesStart,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let name = String::from_utf8_lossy(element.local_name().as_ref()).to_string();
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        let key = String::from_utf8_lossy(attribute.key.local_name().as_ref()).to_string();
+        push_keys(keys, &name, &key, &attribute.unescape_value()?);
+    }
+    Ok(name)
+}
+
+/// Text of the leaf elements, and attributes, of an XML sidecar
+fn xml_keys(contents: &str) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let mut reader = quick_xml::Reader::from_str(contents);
+    let mut keys = Vec::new();
+    let mut elements: Vec<String> = Vec::new();
+    let mut text = String::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => {
+                elements.push(attribute_keys(&mut keys, &element)?);
+                text.clear();
+            }
+            Event::Empty(element) => {
+                attribute_keys(&mut keys, &element)?;
+            }
+            Event::Text(content) => text.push_str(&content.decode()?),
+            Event::CData(content) => text.push_str(&content.decode()?),
+            Event::GeneralRef(entity) => {
+                if let Some(c) = entity.resolve_char_ref()? {
+                    text.push(c);
+                } else if let Some(s) =
+                    quick_xml::escape::resolve_predefined_entity(&entity.decode()?)
+                {
+                    text.push_str(s);
+                }
+            }
+            Event::End(_) => {
+                if let Some(name) = elements.pop() {
+                    if !text.trim().is_empty() {
+                        let parent = elements.last().map_or("", String::as_str);
+                        push_keys(&mut keys, parent, &name, &text);
+                    }
+                }
+                text.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(keys)
+}
+
+/// `key=value` (or `key: value`) lines of an INI sidecar
+fn ini_keys(contents: &str) -> Vec<(String, String)> {
+    let mut keys = Vec::new();
+    let mut section = String::new();
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.to_string();
+        } else if let Some((key, value)) = line.split_once('=').or_else(|| line.split_once(':')) {
+            push_keys(&mut keys, &section, key, value.trim().trim_matches('"'));
+        }
+    }
+    keys
+}
+
+/// First value of an ASCII EXIF/TIFF tag
+fn exif_ascii(exif: &exif::Exif, tag: exif::Tag) -> Option<String> {
+    match &exif.get_field(tag, exif::In::PRIMARY)?.value {
+        exif::Value::Ascii(values) => values
+            .first()
+            .map(|value| String::from_utf8_lossy(value).to_string()),
+        _ => None,
+    }
+}
+
+fn read_exif(path: &Path, metadata: &mut ExportMetadata) -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let exif = match exif::Reader::new().read_from_container(&mut reader) {
+        Ok(exif) => exif,
+        // Exports without EXIF data are common
+        Err(exif::Error::NotFound(_)) | Err(exif::Error::InvalidFormat(_)) => return Ok(()),
+        // Damaged EXIF data only loses its tags, not the rest of the metadata
+        Err(err) => {
+            warn!("Unreadable EXIF data in {}: {}", logging::path(path), err);
+            return Ok(());
+        }
+    };
+    let tags = [
+        (
+            "scan_date",
+            exif::Tag::DateTimeOriginal,
+            "EXIF DateTimeOriginal",
+        ),
+        ("scan_date", exif::Tag::DateTime, "EXIF DateTime"),
+        ("manufacturer", exif::Tag::Make, "EXIF"),
+        ("model", exif::Tag::Model, "EXIF"),
+        ("serial_number", exif::Tag::BodySerialNumber, "EXIF"),
+        ("software", exif::Tag::Software, "EXIF"),
+        ("description", exif::Tag::ImageDescription, "EXIF"),
+    ];
+    for (field, tag, source) in tags {
+        if let Some(value) = exif_ascii(&exif, tag) {
+            metadata.insert(field, &value, source);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use serde::Deserialize;
+use std::path::Path;
+
+/// Built-in rules, tried after any loaded from `--image-type-rules`
+const DEFAULT_RULES: &str = include_str!("image_type_rules.csv");
+
+/// `image_type` of files no rule matches
+pub const UNKNOWN: &str = "unknown";
+
+/// One classifier rule: every non-empty condition must match for `image_type` to be assigned.
+/// A condition lists `|`-separated alternatives, matched as whole words, ignoring case and
+/// punctuation (`red free` matches `Red-Free IR`); `sop_class_uid` alternatives match exactly.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Rule {
+    image_type: String,
+    sop_class_uid: String,
+    modality: String,
+    dicom_image_type: String,
+    series_description: String,
+    protocol: String,
+    acquisition: String,
+}
+
+/// What the classifier looks at, gathered by the extractors
+#[derive(Debug, Default)]
+pub struct ImageTypeInputs<'a> {
+    pub sop_class_uid: &'a str,
+    pub modality: &'a str,
+    /// ImageType (0008,0008), `\`-separated
+    pub dicom_image_type: &'a str,
+    pub series_description: &'a str,
+    /// ProtocolName, or the crystal-eye `protocol`
+    pub protocol: &'a str,
+    /// Illumination, filter, device type and contrast agent descriptions
+    pub acquisition: &'a str,
+}
+
+pub struct ImageTypeRules {
+    rules: Vec<Rule>,
+}
+
+/// Lowercase words of `text`, space-padded so that `contains` matches whole words only
+fn words(text: &str) -> String {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    format!(" {} ", words.join(" "))
+}
+
+fn matches_words(condition: &str, value: &str) -> bool {
+    let value = words(value);
+    condition
+        .split('|')
+        .map(words)
+        .filter(|alternative| alternative.trim() != "")
+        .any(|alternative| value.contains(&alternative))
+}
+
+fn parse_rules(reader: impl std::io::Read) -> Result<Vec<Rule>, csv::Error> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader)
+        .deserialize()
+        // Rows without an image_type, e.g. blank lines, are ignored
+        .filter(|rule: &Result<Rule, csv::Error>| {
+            !matches!(rule, Ok(rule) if rule.image_type.is_empty())
+        })
+        .collect()
+}
+
+impl ImageTypeRules {
+    /// Built-in rules, preceded by those of `rules_file` if given, so sites can extend or override them
+    pub fn load(rules_file: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut rules = match rules_file {
+            Some(path) => parse_rules(std::fs::File::open(path)?)
+                .map_err(|e| format!("invalid image type rules in {:?}: {}", path, e))?,
+            None => Vec::new(),
+        };
+        rules.extend(parse_rules(DEFAULT_RULES.as_bytes())?);
+        Ok(ImageTypeRules { rules })
+    }
+
+    /// Number of rules, built-in ones included
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// `image_type` of the first matching rule, `unknown` if none does
+    pub fn classify(&self, inputs: &ImageTypeInputs) -> String {
+        self.rules
+            .iter()
+            .find(|rule| {
+                (rule.sop_class_uid.is_empty()
+                    || rule
+                        .sop_class_uid
+                        .split('|')
+                        .any(|uid| uid.trim() == inputs.sop_class_uid))
+                    && (rule.modality.is_empty() || matches_words(&rule.modality, inputs.modality))
+                    && (rule.dicom_image_type.is_empty()
+                        || matches_words(&rule.dicom_image_type, inputs.dicom_image_type))
+                    && (rule.series_description.is_empty()
+                        || matches_words(&rule.series_description, inputs.series_description))
+                    && (rule.protocol.is_empty() || matches_words(&rule.protocol, inputs.protocol))
+                    && (rule.acquisition.is_empty()
+                        || matches_words(&rule.acquisition, inputs.acquisition))
+            })
+            .map_or_else(|| UNKNOWN.to_string(), |rule| rule.image_type.clone())
+    }
+}
@@ -0,0 +1,236 @@
+use clap::Parser;
+use duckdb::Connection;
+use std::path::{Path, PathBuf};
+use std::process;
+mod sql;
+use sql::{quote_ident, quote_literal, strptime_expr, DATETIME_FORMATS, DATE_FORMATS};
+
+/// Command line arguments
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Load open-sight CSV files into the DuckDB database used by copy_src", long_about = None)]
+struct Opt {
+    /// CSV files created by open-sight
+    #[arg(name = "CSV_FILES", required = true)]
+    csv_files: Vec<PathBuf>,
+
+    /// Database file to create or update
+    #[arg(short = 'd', long = "database", default_value = "open_sight.duckdb")]
+    database: String,
+
+    /// Table to load into, e.g. open_sight_visual_fields for the visual field side table
+    #[arg(short = 't', long = "table", default_value = "open_sight")]
+    table: String,
+}
+
+#[derive(Default)]
+struct IngestCounts {
+    inserted: usize,
+    duplicate: usize,
+    invalid: usize,
+}
+
+/// SQL type of a crawl CSV column in the database
+fn column_type(column: &str) -> &'static str {
+    match column {
+        "dob" | "scan_date" => "DATE",
+        "modified" | "scan_datetime" => "TIMESTAMP",
+        "file_size" => "BIGINT",
+        "birth_year" | "number_of_frames" | "rows" | "columns" => "INTEGER",
+        "age_at_scan"
+        | "slice_thickness"
+        | "axial_length"
+        | "horizontal_field_of_view"
+        | "emmetropic_magnification"
+        | "intra_ocular_pressure"
+        | "illumination_wave_length"
+        | "depth_spatial_resolution"
+        | "along_scan_spatial_resolution"
+        | "across_scan_spatial_resolution"
+        | "mean_deviation"
+        | "pattern_standard_deviation"
+        | "visual_field_index"
+        | "false_positives_estimate"
+        | "false_negatives_estimate"
+        | "foveal_sensitivity"
+        | "test_duration"
+        | "axial_length_right"
+        | "axial_length_left" => "DOUBLE",
+        "fixation_checked" | "fixation_losses" => "INTEGER",
+        "file_path" | "file_id" => "VARCHAR PRIMARY KEY",
+        _ => "VARCHAR",
+    }
+}
+
+/// Expression converting a staged VARCHAR column to its database type, NULL if empty
+fn cast_expr(column: &str) -> String {
+    let col = quote_ident(column);
+    match column_type(column) {
+        "DATE" => format!("CAST({} AS DATE)", strptime_expr(&col, DATE_FORMATS)),
+        "TIMESTAMP" => strptime_expr(&col, DATETIME_FORMATS),
+        "BIGINT" | "INTEGER" | "DOUBLE" => {
+            format!("TRY_CAST(NULLIF({col}, '') AS {})", column_type(column))
+        }
+        _ => col,
+    }
+}
+
+/// Condition true when a non-empty staged value cannot be converted
+fn invalid_expr(column: &str) -> Option<String> {
+    match column_type(column) {
+        "DATE" | "TIMESTAMP" | "BIGINT" | "INTEGER" | "DOUBLE" => Some(format!(
+            "(NULLIF({}, '') IS NOT NULL AND {} IS NULL)",
+            quote_ident(column),
+            cast_expr(column)
+        )),
+        _ => None,
+    }
+}
+
+fn table_columns(conn: &Connection, table: &str) -> Result<Vec<String>, duckdb::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT column_name FROM information_schema.columns WHERE table_name = ? ORDER BY ordinal_position",
+    )?;
+    let columns = stmt
+        .query_map([table], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(columns)
+}
+
+/// Create the table from the CSV header, or add the columns it does not have yet
+fn ensure_table(conn: &Connection, table: &str, columns: &[String]) -> Result<(), duckdb::Error> {
+    let existing = table_columns(conn, table)?;
+    if existing.is_empty() {
+        let defs: Vec<String> = columns
+            .iter()
+            .map(|c| format!("{} {}", quote_ident(c), column_type(c)))
+            .collect();
+        conn.execute_batch(&format!(
+            "CREATE TABLE {} ({});",
+            quote_ident(table),
+            defs.join(", ")
+        ))?;
+    } else {
+        for column in columns.iter().filter(|c| !existing.contains(c)) {
+            conn.execute_batch(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {};",
+                quote_ident(table),
+                quote_ident(column),
+                column_type(column).trim_end_matches(" PRIMARY KEY")
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+fn ingest_csv(
+    conn: &Connection,
+    table: &str,
+    csv_file: &Path,
+) -> Result<IngestCounts, Box<dyn std::error::Error>> {
+    let csv_path = csv_file.to_str().ok_or("Invalid CSV file path")?;
+
+    conn.execute_batch("BEGIN TRANSACTION;")?;
+    // Everything is staged as text so that dates are parsed with an explicit format below
+    conn.execute_batch(&format!(
+        "CREATE OR REPLACE TEMP TABLE staging AS SELECT * FROM read_csv({}, header = true, all_varchar = true, auto_detect = true);",
+        quote_literal(csv_path)
+    ))?;
+
+    let mut stmt = conn.prepare(
+        "SELECT column_name FROM information_schema.columns WHERE table_name = 'staging' ORDER BY ordinal_position",
+    )?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    // Research-safe indexes have a keyed `file_id` instead of `file_path`
+    let key = ["file_path", "file_id"]
+        .into_iter()
+        .find(|key| columns.iter().any(|c| c == key))
+        .ok_or("CSV has no file_path or file_id column")?;
+    ensure_table(conn, table, &columns)?;
+
+    let mut invalid_conditions = vec![format!("NULLIF({}, '') IS NULL", quote_ident(key))];
+    invalid_conditions.extend(columns.iter().filter_map(|c| invalid_expr(c)));
+    let invalid_condition = invalid_conditions.join(" OR ");
+
+    let total: usize = conn.query_row("SELECT count(*) FROM staging", [], |row| row.get(0))?;
+    let invalid: usize = conn.query_row(
+        &format!("SELECT count(*) FROM staging WHERE {}", invalid_condition),
+        [],
+        |row| row.get(0),
+    )?;
+
+    let column_list: Vec<String> = columns.iter().map(|c| quote_ident(c)).collect();
+    let select_list: Vec<String> = columns.iter().map(|c| cast_expr(c)).collect();
+    // Keep the first row per file_path (or file_id), skipping those already in the table
+    let inserted = conn.execute(
+        &format!(
+            "INSERT INTO {table} ({cols})
+             SELECT {selects}
+             FROM (
+                 SELECT *, row_number() OVER (PARTITION BY {key} ORDER BY rowid) AS rn
+                 FROM staging
+                 WHERE NOT ({invalid})
+             ) AS csv
+             WHERE rn = 1
+               AND NOT EXISTS (SELECT 1 FROM {table} WHERE {table}.{key} = csv.{key})",
+            table = quote_ident(table),
+            cols = column_list.join(", "),
+            selects = select_list.join(", "),
+            invalid = invalid_condition,
+            key = quote_ident(key),
+        ),
+        [],
+    )?;
+    conn.execute_batch("DROP TABLE staging; COMMIT;")?;
+
+    Ok(IngestCounts {
+        inserted,
+        duplicate: total - invalid - inserted,
+        invalid,
+    })
+}
+
+fn main() {
+    let args = Opt::parse();
+
+    let conn = Connection::open(&args.database).unwrap_or_else(|err| {
+        eprintln!("Error connecting to database: {}", err);
+        process::exit(1);
+    });
+
+    let mut totals = IngestCounts::default();
+    for csv_file in &args.csv_files {
+        match ingest_csv(&conn, &args.table, csv_file) {
+            Ok(counts) => {
+                println!(
+                    ">> {}: inserted {}, duplicate {}, invalid {}",
+                    csv_file.display(),
+                    counts.inserted,
+                    counts.duplicate,
+                    counts.invalid
+                );
+                totals.inserted += counts.inserted;
+                totals.duplicate += counts.duplicate;
+                totals.invalid += counts.invalid;
+            }
+            Err(e) => {
+                let _ = conn.execute_batch("ROLLBACK;");
+                eprintln!("Error ingesting {}: {}", csv_file.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let rows: usize = conn
+        .query_row(
+            &format!("SELECT count(*) FROM {}", quote_ident(&args.table)),
+            [],
+            |row| row.get(0),
+        )
+        .unwrap_or_default();
+    println!(
+        ">> Total: inserted {}, duplicate {}, invalid {} | {} rows in {} of {}",
+        totals.inserted, totals.duplicate, totals.invalid, rows, args.table, args.database
+    );
+}
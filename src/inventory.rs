@@ -0,0 +1,179 @@
+use log::{error, info};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::logging;
+use crate::walk::WalkOptions;
+
+/// Magic signatures, as the offset and bytes a file starts with
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (128, b"DICM", "dicom"),
+    (0, b"PK\x03\x04", "zip"),
+    (0, b"PK\x05\x06", "zip"),
+    (257, b"ustar", "tar"),
+    (0, b"\x1f\x8b", "gzip"),
+    (0, b"\x28\xb5\x2f\xfd", "zstd"),
+    (0, b"BZh", "bzip2"),
+    (0, b"\xfd7zXZ\x00", "xz"),
+    (0, b"7z\xbc\xaf\x27\x1c", "7z"),
+    (0, b"Rar!\x1a\x07", "rar"),
+    (0, b"\xff\xd8\xff", "jpeg"),
+    (0, b"\x89PNG\r\n\x1a\n", "png"),
+    (0, b"II*\x00", "tiff"),
+    (0, b"MM\x00*", "tiff"),
+    (0, b"BM", "bmp"),
+    (0, b"%PDF", "pdf"),
+    (0, b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1", "ole2"),
+    (0, b"<?xml", "xml"),
+];
+
+/// Files of a root sharing an extension, a signature and whether open-sight indexes them
+#[derive(Debug, Serialize)]
+struct InventoryRow {
+    root: String,
+    extension: String,
+    signature: &'static str,
+    recognised: bool,
+    files: u64,
+    total_size: u64,
+    example_path: String,
+}
+
+/// Signature of a file from its first bytes: a known format, `text`, `empty` or `unknown`
+fn signature(path: &Path) -> std::io::Result<&'static str> {
+    let mut head = Vec::with_capacity(512);
+    File::open(path)?.take(512).read_to_end(&mut head)?;
+    if head.is_empty() {
+        return Ok("empty");
+    }
+    let known = SIGNATURES
+        .iter()
+        .find(|(offset, magic, _)| head.get(*offset..offset + magic.len()) == Some(*magic));
+    if let Some((_, _, name)) = known {
+        return Ok(name);
+    }
+    let text = std::str::from_utf8(&head).is_ok_and(|text| {
+        text.chars()
+            .all(|c| !c.is_control() || c.is_ascii_whitespace())
+    });
+    Ok(if text { "text" } else { "unknown" })
+}
+
+/// Tally every file under each root by extension and signature, instead of indexing them,
+/// and write the tally to a CSV, types open-sight does not index first. Files referenced by
+/// another one (the instances of a DICOMDIR) are indexed whatever their name.
+pub fn run(
+    roots: &[PathBuf],
+    output_path: &Path,
+    walk_options: &WalkOptions,
+    recognised: &dyn Fn(&Path) -> bool,
+    referenced: &dyn Fn(&Path) -> Vec<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut rows = Vec::new();
+    for root in roots {
+        info!("Taking inventory of {}", logging::path(root));
+        let mut tally: BTreeMap<(String, &'static str, bool), (u64, u64, PathBuf)> =
+            BTreeMap::new();
+        let mut add = |path: &Path, extension: String, signature, recognised, size| {
+            let counts = tally
+                .entry((extension, signature, recognised))
+                .or_insert_with(|| (0, 0, path.to_path_buf()));
+            counts.0 += 1;
+            counts.1 += size;
+        };
+        // References may come after the files they reference, so only the files not
+        // recognised otherwise wait for the end of the walk
+        let mut candidates = Vec::new();
+        let mut referenced_files = HashSet::new();
+        for entry in walk_options.walk(root) {
+            let entry = match entry {
+                Ok(entry) if entry.file_type().is_file() => entry,
+                Ok(_) => continue,
+                Err(err) => {
+                    error!("{}", logging::walk_error(&err));
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let size = entry.metadata().map(|m| m.len()).unwrap_or_default();
+            let signature = match signature(path) {
+                Ok(signature) => signature,
+                Err(err) => {
+                    error!(
+                        "Error reading {}: {}",
+                        logging::path(path),
+                        logging::error_for(&err, path)
+                    );
+                    "unreadable"
+                }
+            };
+            let extension = path
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            referenced_files.extend(referenced(path));
+            if recognised(path) || referenced_files.contains(path) {
+                add(path, extension, signature, true, size);
+            } else {
+                candidates.push((path.to_path_buf(), extension, signature, size));
+            }
+        }
+        for (path, extension, signature, size) in candidates {
+            let recognised = referenced_files.contains(&path);
+            add(&path, extension, signature, recognised, size);
+        }
+
+        let mut root_rows: Vec<InventoryRow> = tally
+            .into_iter()
+            .map(
+                |((extension, signature, recognised), (files, total_size, example))| InventoryRow {
+                    root: root.to_string_lossy().to_string(),
+                    extension,
+                    signature,
+                    recognised,
+                    files,
+                    total_size,
+                    example_path: example.to_string_lossy().to_string(),
+                },
+            )
+            .collect();
+        root_rows.sort_by(|a, b| {
+            a.recognised
+                .cmp(&b.recognised)
+                .then(b.total_size.cmp(&a.total_size))
+        });
+
+        let files: u64 = root_rows.iter().map(|row| row.files).sum();
+        let unrecognised: Vec<&InventoryRow> =
+            root_rows.iter().filter(|row| !row.recognised).collect();
+        info!(
+            "{}: {} files, {} not indexed ({} bytes)",
+            logging::path(root),
+            files,
+            unrecognised.iter().map(|row| row.files).sum::<u64>(),
+            unrecognised.iter().map(|row| row.total_size).sum::<u64>()
+        );
+        for row in unrecognised {
+            info!(
+                "  .{} {}: {} files, {} bytes, e.g. {}",
+                row.extension,
+                row.signature,
+                row.files,
+                row.total_size,
+                logging::path(Path::new(&row.example_path))
+            );
+        }
+        rows.extend(root_rows);
+    }
+
+    let mut wtr = csv::Writer::from_path(output_path)?;
+    for row in &rows {
+        wtr.serialize(row)?;
+    }
+    wtr.flush()?;
+    info!("Inventory saved to {}", logging::path(output_path));
+    Ok(())
+}
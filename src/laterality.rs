@@ -0,0 +1,94 @@
+use dicom_dictionary_std::tags;
+use dicom_object::InMemDicomObject;
+
+use crate::element_str;
+
+/// Laterality when no source gives it
+pub const UNKNOWN: &str = "U";
+
+/// `R`, `L` or `B` for the usual spellings (`OD`/`OS`/`OU`, `RIGHT`, `Left eye`...), else `None`
+pub fn normalise(value: &str) -> Option<&'static str> {
+    match value.trim().to_uppercase().as_str() {
+        "R" | "OD" | "RE" | "RIGHT" | "RIGHT EYE" => Some("R"),
+        "L" | "OS" | "LE" | "LEFT" | "LEFT EYE" => Some("L"),
+        "B" | "OU" | "BE" | "BOTH" | "BOTH EYES" | "BILATERAL" => Some("B"),
+        _ => None,
+    }
+}
+
+/// Laterality named by whole-word tokens of a free-text description, e.g. `OCT OD` or
+/// `Macula Left`; `B` if it names both eyes
+pub fn from_description(text: &str) -> Option<&'static str> {
+    let (mut right, mut left) = (false, false);
+    for token in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+    {
+        match token.to_uppercase().as_str() {
+            "R" | "OD" | "RIGHT" => right = true,
+            "L" | "OS" | "LEFT" => left = true,
+            "OU" | "BOTH" | "BILATERAL" => (right, left) = (true, true),
+            _ => {}
+        }
+    }
+    match (right, left) {
+        (true, true) => Some("B"),
+        (true, false) => Some("R"),
+        (false, true) => Some("L"),
+        (false, false) => None,
+    }
+}
+
+/// FrameLaterality of the shared functional groups, as used by multi-frame objects
+fn frame_laterality(obj: &InMemDicomObject) -> Result<String, Box<dyn std::error::Error>> {
+    let shared = obj
+        .element_opt(tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE)?
+        .and_then(|elem| elem.items()?.first());
+    let anatomy = match shared {
+        Some(shared) => shared
+            .element_opt(tags::FRAME_ANATOMY_SEQUENCE)?
+            .and_then(|elem| elem.items()?.first()),
+        None => None,
+    };
+    match anatomy {
+        Some(anatomy) => element_str(anatomy, tags::FRAME_LATERALITY),
+        None => Ok(String::new()),
+    }
+}
+
+/// Normalised laterality of a DICOM object and the attribute it came from, in order of
+/// preference: ImageLaterality, Laterality, FrameLaterality, MeasurementLaterality, then
+/// tokens of SeriesDescription and ProtocolName. `U` with an empty source if none gives it.
+pub fn dicom_laterality(
+    obj: &InMemDicomObject,
+) -> Result<(&'static str, &'static str), Box<dyn std::error::Error>> {
+    let coded = [
+        ("ImageLaterality", element_str(obj, tags::IMAGE_LATERALITY)?),
+        ("Laterality", element_str(obj, tags::LATERALITY)?),
+        ("FrameLaterality", frame_laterality(obj)?),
+        (
+            "MeasurementLaterality",
+            element_str(obj, tags::MEASUREMENT_LATERALITY)?,
+        ),
+    ];
+    for (source, value) in coded {
+        if let Some(laterality) = normalise(&value) {
+            return Ok((laterality, source));
+        }
+    }
+
+    let described = [
+        (
+            "SeriesDescription",
+            element_str(obj, tags::SERIES_DESCRIPTION)?,
+        ),
+        ("ProtocolName", element_str(obj, tags::PROTOCOL_NAME)?),
+    ];
+    for (source, value) in described {
+        if let Some(laterality) = from_description(&value) {
+            return Ok((laterality, source));
+        }
+    }
+
+    Ok((UNKNOWN, ""))
+}
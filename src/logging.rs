@@ -0,0 +1,128 @@
+use chrono::Local;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::helpers::sha256_hex;
+
+static REDACT: AtomicBool = AtomicBool::new(false);
+
+/// Console logger mirroring the historical `>>` / `ERROR:` output, optionally teed to a file
+struct Logger {
+    level: LevelFilter,
+    file: Option<Mutex<File>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error => eprintln!("ERROR: {}", record.args()),
+            Level::Warn => eprintln!(">> WARNING: {}", record.args()),
+            Level::Info => println!(">> {}", record.args()),
+            Level::Debug | Level::Trace => eprintln!(">> DEBUG: {}", record.args()),
+        }
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = writeln!(
+                    file,
+                    "{} [{}] {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    record.level(),
+                    record.args()
+                );
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+/// Install the global logger, appending to `log_file` if given
+pub fn init(
+    level: LevelFilter,
+    log_file: Option<&Path>,
+    redact: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = match log_file {
+        Some(path) => Some(Mutex::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        None => None,
+    };
+    REDACT.store(redact, Ordering::Relaxed);
+    log::set_boxed_logger(Box::new(Logger { level, file }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Stable file ID: first 12 hex digits of the SHA-256 of the canonical path.
+/// It matches `left(sha256(file_path), 12)` in DuckDB, so it can be looked up in the index.
+pub fn file_id(path: &Path) -> String {
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let hex = sha256_hex(canonical.to_string_lossy().as_bytes());
+    format!("file:{}", &hex[..12])
+}
+
+/// Path as it should appear in logs
+pub fn path(path: &Path) -> String {
+    if REDACT.load(Ordering::Relaxed) {
+        file_id(path)
+    } else {
+        format!("{:?}", path)
+    }
+}
+
+/// List of paths as it should appear in logs
+pub fn paths<P: AsRef<Path>>(paths: &[P]) -> String {
+    let paths: Vec<String> = paths.iter().map(|p| path(p.as_ref())).collect();
+    format!("[{}]", paths.join(", "))
+}
+
+/// Error message with any occurrence of `path` replaced by its file ID when redacting
+pub fn error_for(err: &dyn Display, path: &Path) -> String {
+    let msg = err.to_string();
+    if !REDACT.load(Ordering::Relaxed) {
+        return msg;
+    }
+    let id = file_id(path);
+    let mut msg = msg.replace(&*path.to_string_lossy(), &id);
+    if let Ok(canonical) = path.canonicalize() {
+        msg = msg.replace(&*canonical.to_string_lossy(), &id);
+    }
+    msg
+}
+
+/// Error of a directory walk as it should appear in logs: only the file ID and the kind of
+/// error when redacting, as the error shows the path
+pub fn walk_error(err: &walkdir::Error) -> String {
+    match err.path() {
+        Some(path) if redacting() => format!(
+            "{}: {:?}",
+            self::path(path),
+            err.io_error().map(|e| e.kind())
+        ),
+        _ => format!("{:?}", err),
+    }
+}
+
+/// Whether paths are redacted in logs
+pub fn redacting() -> bool {
+    REDACT.load(Ordering::Relaxed)
+}
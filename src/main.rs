@@ -1,30 +1,76 @@
-use chrono::{DateTime, Datelike, Local, NaiveDate};
+use chrono::{Local, NaiveDateTime};
 use clap::Parser;
 use dicom_dictionary_std::tags;
-use dicom_object::OpenFileOptions;
+use dicom_object::file::ReadPreamble;
+use dicom_object::{DefaultDicomObject, InMemDicomObject, OpenFileOptions, Tag};
+use log::{error, info, warn, LevelFilter};
 use rayon::prelude::*;
+use regex::Regex;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashSet;
 use std::fs::{File, OpenOptions};
-use std::io::BufReader;
 use std::io::Write;
+use std::io::{self, BufReader, Read};
 use std::path::{Path, PathBuf};
-use std::time::{Instant, SystemTime};
-use std::{env, fs, io};
+use std::time::Instant;
+use std::{env, fs};
 use std::{process::Command, thread, time::Duration};
 use sysinfo::System;
-use tempfile::tempdir;
-use walkdir::WalkDir;
+use tempfile::{tempdir, TempDir};
+mod archive;
+mod compression;
+mod dates;
+mod dicomdir;
+mod exclusions;
 mod helpers;
+mod image_export;
+mod image_type;
+mod inventory;
+mod laterality;
+mod logging;
+mod manufacturer;
+mod ophthalmic;
+mod path_fields;
+mod person_name;
+mod placeholder_dates;
+mod pseudonymise;
+mod roots;
+mod walk;
+use dates::{
+    age_and_date_flags, format_date, format_modified_datetime, parse_dicom_date_time,
+    parse_dicom_datetime, parse_dicom_time, parse_loose_date, parse_loose_datetime, DateFormat,
+};
+use dicomdir::DicomdirRecord;
+use exclusions::PatientExclusions;
 use helpers::handle_output_path;
+use image_type::{ImageTypeInputs, ImageTypeRules};
+use path_fields::PathPattern;
+use person_name::PersonName;
+use pseudonymise::{DobMode, Pseudonymiser};
+use roots::Root;
+use walk::WalkOptions;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(required = true)]
+    #[arg(required_unless_present_any = ["files_from", "roots"])]
     folder_paths: Vec<PathBuf>,
 
+    #[arg(
+        long = "root",
+        value_parser = roots::parse_root,
+        help = "Folder to index as LABEL=PATH, its files getting root_label and relative_path (repeatable)"
+    )]
+    roots: Vec<Root>,
+
+    #[arg(
+        long,
+        conflicts_with = "inventory",
+        help = "Also index the paths listed in this file, or `-` for stdin, one per line or NUL-separated"
+    )]
+    files_from: Option<PathBuf>,
+
     #[arg(short, long, default_value = "open_sight_results.csv")]
     csv_out: String,
 
@@ -36,22 +82,304 @@ struct Args {
 
     #[arg(short, long, default_value_t = 50)]
     batch_size: usize,
+
+    #[arg(
+        long,
+        requires = "secret_file",
+        help = "Write a research-safe index: HMAC pseudonym for patient ID, no name, reduced DOB"
+    )]
+    pseudonymise: bool,
+
+    #[arg(
+        long,
+        help = "File holding the HMAC secret, keep it apart from the index"
+    )]
+    secret_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value = "open_sight_pseudonyms.csv",
+        help = "Where the pseudonym to patient ID mapping table is written"
+    )]
+    mapping_out: PathBuf,
+
+    #[arg(long, value_enum, default_value_t = DobMode::Year, help = "How DOB is reduced when pseudonymising")]
+    dob_mode: DobMode,
+
+    #[arg(long, value_enum, default_value_t = DateFormat::Iso, help = "How dates are written")]
+    date_format: DateFormat,
+
+    #[arg(
+        long,
+        help = "File of opted-out patient IDs, one per line, plain or as `sha256:<hex digest>`"
+    )]
+    exclude_patients: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "CSV of extra image type rules, tried before the built-in ones (see src/image_type_rules.csv)"
+    )]
+    image_type_rules: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Warn when a DICOMDIR record differs from the header of the file it references"
+    )]
+    check_dicomdir: bool,
+
+    #[arg(
+        long,
+        help = "Also index JPEG, PNG and TIFF image exports, with their XML/INI sidecar"
+    )]
+    image_exports: bool,
+
+    #[arg(
+        long,
+        requires = "image_exports",
+        help = "Regex on image export file names, with named groups such as patient_id, scan_date and laterality (as --path-pattern)"
+    )]
+    filename_pattern: Option<String>,
+
+    #[arg(
+        long,
+        help = "Fields to read from file paths, as [ROOT=>]PATTERN: a template such as '{patient_id}/{scan_date}/*' or a regex with named groups (repeatable)"
+    )]
+    path_pattern: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Only index files matching one of these globs, on their name or path below the folder (repeatable)"
+    )]
+    include: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Skip files and folders matching any of these globs, on their name or path below the folder (repeatable)"
+    )]
+    exclude: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Walk at most this many folders down (0: only the given paths)"
+    )]
+    max_depth: Option<usize>,
+
+    #[arg(long, help = "Follow symbolic links, walking each folder once")]
+    follow_symlinks: bool,
+
+    #[arg(long, help = "Skip files and folders whose name starts with a dot")]
+    skip_hidden: bool,
+
+    #[arg(long, value_parser = walk::parse_size, help = "Skip files smaller than this size, in bytes or with a K, M or G suffix")]
+    min_size: Option<u64>,
+
+    #[arg(long, value_parser = walk::parse_size, help = "Skip files larger than this size, in bytes or with a K, M or G suffix")]
+    max_size: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Only take an inventory of the files found, by extension and signature, into this CSV"
+    )]
+    inventory: Option<PathBuf>,
+
+    #[arg(long, default_value_t = LevelFilter::Info, help = "Log level: off, error, warn, info or debug")]
+    log_level: LevelFilter,
+
+    #[arg(long, help = "Also append log messages, with timestamps, to this file")]
+    log_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Replace file paths in logs by stable file IDs, see `left(sha256(file_path), 12)`"
+    )]
+    redact_logs: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct DicomData {
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DicomData {
     patient_id: String,
     patient_name: String,
+    patient_family_name: String,
+    patient_given_name: String,
+    patient_middle_name: String,
+    patient_name_prefix: String,
+    patient_name_suffix: String,
     laterality: String,
+    laterality_source: String,
     sex: String,
     dob: String,
     scan_date: String,
+    scan_datetime: String,
+    scan_datetime_source: String,
+    age_at_scan: String,
+    date_flags: String,
+    source_format: String,
+    // From --path-pattern
+    path_patient_id: String,
+    path_scan_date: String,
+    path_fields: String,
+    path_flags: String,
     modality: String,
+    image_type: String,
     manufacturer: String,
+    manufacturer_normalised: String,
+    manufacturer_model_name: String,
+    device_serial_number: String,
+    software_versions: String,
+    station_name: String,
+    institution_name: String,
     series_description: String,
+    sop_class_uid: String,
+    sop_instance_uid: String,
+    // Ophthalmic Tomography only
+    number_of_frames: String,
+    rows: String,
+    columns: String,
+    pixel_spacing: String,
+    slice_thickness: String,
+    axial_length: String,
+    horizontal_field_of_view: String,
+    emmetropic_magnification: String,
+    intra_ocular_pressure: String,
+    pupil_dilated: String,
+    detector_type: String,
+    illumination_wave_length: String,
+    depth_spatial_resolution: String,
+    along_scan_spatial_resolution: String,
+    across_scan_spatial_resolution: String,
+    refractive_state: String,
+    acquisition_device_type: String,
     modified: String,
     file_size: u64,
+    // From --root
+    root_label: String,
+    relative_path: String,
     file_path: String,
+    // Written to a side table instead
+    #[serde(skip)]
+    side_data: Option<ophthalmic::SideData>,
+    // Checked against the opt-outs, not written
+    #[serde(skip)]
+    dicomdir_patient_id: String,
+}
+
+impl DicomData {
+    /// Every patient ID the row carries: of the header, of the file path and of the DICOMDIR
+    /// record, any of which may belong to an opted-out patient
+    fn patient_ids(&self) -> [&str; 3] {
+        [
+            &self.patient_id,
+            &self.path_patient_id,
+            &self.dicomdir_patient_id,
+        ]
+    }
+
+    /// Age at scan and flags for doubtful dates, from `dob` and `scan_date`
+    fn set_age_and_date_flags(&mut self, dob_two_digit_year: bool) {
+        (self.age_at_scan, self.date_flags) = age_and_date_flags(
+            &self.dob,
+            &self.scan_date,
+            dob_two_digit_year,
+            Local::now().date_naive(),
+        );
+    }
+
+    /// Patient name as `family^given^middle^prefix^suffix`, and its components
+    fn set_patient_name(&mut self, name: PersonName) {
+        self.patient_name = name.to_pn();
+        self.patient_family_name = name.family;
+        self.patient_given_name = name.given;
+        self.patient_middle_name = name.middle;
+        self.patient_name_prefix = name.prefix;
+        self.patient_name_suffix = name.suffix;
+    }
+
+    /// Fields read from the file path, `patient_id` and `scan_date` compared with the header
+    fn set_path_fields(&mut self, patterns: &[PathPattern], date_format: DateFormat) {
+        let fields = path_fields::path_fields(patterns, &self.file_path);
+        let field = |name| {
+            fields
+                .iter()
+                .find(|(field, _)| field == name)
+                .map_or("", |(_, value)| value.trim())
+        };
+        self.path_patient_id = field("patient_id").to_string();
+        self.path_scan_date = parse_loose_date(field("scan_date"))
+            .map(|date| date_format.format(date))
+            .unwrap_or_default();
+        self.path_fields = fields
+            .iter()
+            .map(|(field, value)| format!("{}={}", field, value))
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let mut flags = Vec::new();
+        if !self.path_patient_id.is_empty()
+            && !self.patient_id.trim().is_empty()
+            && !self
+                .path_patient_id
+                .eq_ignore_ascii_case(self.patient_id.trim())
+        {
+            flags.push("patient_id_mismatch");
+        }
+        if !self.path_scan_date.is_empty()
+            && !self.scan_date.is_empty()
+            && self.path_scan_date != self.scan_date
+        {
+            flags.push("scan_date_mismatch");
+        }
+        if !field("scan_date").is_empty() && self.path_scan_date.is_empty() {
+            flags.push("path_scan_date_unparsed");
+        }
+        self.path_flags = flags.join(";");
+    }
+
+    /// Label of the root the file is under, the innermost one, and its path below it, with `/`
+    /// separators whatever the platform
+    fn set_root(&mut self, roots: &[Root]) {
+        let file_path = Path::new(&self.file_path);
+        let found = roots
+            .iter()
+            .filter_map(|root| Some((root, file_path.strip_prefix(&root.path).ok()?)))
+            .max_by_key(|(root, _)| root.path.components().count());
+        if let Some((root, relative_path)) = found {
+            self.root_label = root.label.clone();
+            self.relative_path = relative_path
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+        }
+    }
+
+    /// Path of the file, in the side table rows too
+    fn set_file_path(&mut self, file_path: &str) {
+        self.file_path = file_path.to_string();
+        if let Some(side_data) = &mut self.side_data {
+            side_data.set_file_path(file_path);
+        }
+    }
+}
+
+/// File to process, on disk or extracted from an archive
+struct InputFile {
+    path: PathBuf,
+    /// Path of the member in the archive, `archive.zip!/inner/path.dcm`, written as `file_path`
+    archive_path: Option<String>,
+    /// DICOMDIR record referencing the file, which is DICOM whatever its name
+    dicomdir_record: Option<DicomdirRecord>,
+    /// Named in `--files-from` with no known extension, read as DICOM whatever its name
+    listed: bool,
+}
+
+impl AsRef<Path> for InputFile {
+    /// Path to show in logs
+    fn as_ref(&self) -> &Path {
+        match &self.archive_path {
+            Some(archive_path) => Path::new(archive_path),
+            None => &self.path,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -73,6 +401,15 @@ struct PatientData {
 #[derive(Deserialize, Debug)]
 struct ExamData {
     manufacturer: Option<String>,
+    #[serde(alias = "model", alias = "device_model")]
+    manufacturer_model: Option<String>,
+    #[serde(alias = "serial_number", alias = "device_serial_number")]
+    scanner_serial_number: Option<String>,
+    #[serde(alias = "software_version", alias = "software_versions")]
+    scanner_software_version: Option<String>,
+    station_name: Option<String>,
+    #[serde(alias = "institution")]
+    institution_name: Option<String>,
     scan_datetime: Option<String>,
 }
 
@@ -82,7 +419,101 @@ struct SeriesData {
     protocol: Option<String>,
 }
 
+/// Where the files to index come from
+enum Source {
+    /// Folder (or single file) to walk
+    Folder(PathBuf),
+    /// Paths given by `--files-from`, listed folders being walked
+    FileList(Vec<PathBuf>),
+}
+
+/// Walked paths, with whether each is a file
+fn walk_entries<'a>(
+    walk_options: &'a WalkOptions,
+    root: &'a Path,
+) -> impl Iterator<Item = walkdir::Result<Entry>> + 'a {
+    walk_options.walk(root).map(|entry| {
+        entry.map(|entry| Entry {
+            is_file: entry.file_type().is_file(),
+            path: entry.into_path(),
+            listed: false,
+        })
+    })
+}
+
+/// File or folder of a source
+struct Entry {
+    path: PathBuf,
+    is_file: bool,
+    /// Named in `--files-from`, rather than found by walking a folder
+    listed: bool,
+}
+
+/// Paths listed in a file, or stdin for `-`: NUL-separated if there is any NUL, otherwise
+/// one per line. Empty lines are skipped.
+fn read_file_list(list: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut contents = Vec::new();
+    if list == Path::new("-") {
+        io::stdin().lock().read_to_end(&mut contents)?;
+    } else {
+        File::open(list)?.read_to_end(&mut contents)?;
+    }
+    let paths: Vec<&[u8]> = if contents.contains(&0) {
+        contents.split(|&byte| byte == 0).collect()
+    } else {
+        contents
+            .split(|&byte| byte == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .collect()
+    };
+    Ok(paths
+        .into_iter()
+        .filter(|path| !path.is_empty())
+        .map(path_from_bytes)
+        .collect())
+}
+
+/// Path of a listed name, byte for byte: names need not be UTF-8 on unix
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).as_ref())
+}
+
+/// Settings shared by the extractors
+struct ExtractOptions {
+    crystal_eye_path: String,
+    date_format: DateFormat,
+    image_type_rules: ImageTypeRules,
+    check_dicomdir: bool,
+    image_exports: bool,
+    filename_pattern: Option<Regex>,
+    path_patterns: Vec<PathPattern>,
+    roots: Vec<Root>,
+}
+
 const CE_EXT: &[&str] = &["e2e", "fda", "sdb", "dcm"];
+
+fn has_ce_ext(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        CE_EXT
+            .iter()
+            .any(|ext_pattern| ext.eq_ignore_ascii_case(ext_pattern))
+    })
+}
+
+/// Files to index: DICOM and crystal-eye files, compressed DICOM files and, if asked, image exports
+fn is_input_file(path: &Path, image_exports: bool) -> bool {
+    has_ce_ext(path)
+        || compression::is_compressed_dicom(path)
+        || (image_exports && image_export::source_format(path).is_some())
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Start measuring time
     let start_time = Instant::now();
@@ -94,12 +525,118 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse command line arguments using Clap
     let args = Args::parse();
 
+    logging::init(args.log_level, args.log_file.as_deref(), args.redact_logs)?;
+
     // Use the parsed arguments
-    let folder_paths = args.folder_paths;
+    let mut folder_paths = args.folder_paths;
     let csv_out = &args.csv_out;
     let num_jobs = args.num_jobs;
     let overwrite = args.overwrite;
     let batch_size = args.batch_size;
+    let mut date_format = args.date_format;
+
+    // Roots are walked as the other folders, canonical to match the canonical file paths
+    let mut roots = args.roots;
+    for root in &mut roots {
+        root.path = root
+            .path
+            .canonicalize()
+            .map_err(|err| format!("Root {} ({}): {}", root.label, root.path.display(), err))?;
+    }
+    // A label must lead to one folder, for copy_src to find files again
+    let mut labels = HashSet::new();
+    if let Some(root) = roots
+        .iter()
+        .find(|root| !labels.insert(root.label.as_str()))
+    {
+        return Err(format!("Root label {} given more than once", root.label).into());
+    }
+    folder_paths.extend(roots.iter().map(|root| root.path.clone()));
+    let folder_paths = outermost_folders(folder_paths);
+
+    let walk_options = WalkOptions {
+        include: walk::parse_patterns(&args.include)?,
+        exclude: walk::parse_patterns(&args.exclude)?,
+        max_depth: args.max_depth,
+        follow_symlinks: args.follow_symlinks,
+        skip_hidden: args.skip_hidden,
+        min_size: args.min_size,
+        max_size: args.max_size,
+    };
+
+    // Inventory mode: nothing is indexed
+    if let Some(inventory_path) = &args.inventory {
+        return inventory::run(
+            &folder_paths,
+            inventory_path,
+            &walk_options,
+            &|path| {
+                is_input_file(path, args.image_exports)
+                    || archive::is_archive(&path.to_string_lossy())
+                    || dicomdir::is_dicomdir(path)
+            },
+            &|path| {
+                if !dicomdir::is_dicomdir(path) {
+                    return Vec::new();
+                }
+                match dicomdir::read_records(path) {
+                    Ok(records) => records.into_iter().map(|record| record.path).collect(),
+                    Err(err) => {
+                        error!(
+                            "Error reading DICOMDIR {}: {}",
+                            logging::path(path),
+                            logging::error_for(&err, path)
+                        );
+                        Vec::new()
+                    }
+                }
+            },
+        );
+    }
+
+    let image_type_rules = ImageTypeRules::load(args.image_type_rules.as_deref())?;
+    if args.image_type_rules.is_some() {
+        info!("Loaded {} image type rules", image_type_rules.rule_count());
+    }
+    let filename_pattern = args
+        .filename_pattern
+        .as_deref()
+        .map(Regex::new)
+        .transpose()?;
+    let path_patterns = args
+        .path_pattern
+        .iter()
+        .map(|pattern| PathPattern::parse(pattern))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let exclusions = match &args.exclude_patients {
+        Some(path) => {
+            let exclusions = PatientExclusions::load(path)?;
+            info!("Loaded {} patient opt-outs", exclusions.entries());
+            Some(exclusions)
+        }
+        None => None,
+    };
+
+    let mut pseudonymiser = None;
+    if args.pseudonymise {
+        if args.mapping_out == Path::new(csv_out) {
+            return Err("--mapping-out must differ from --csv-out".into());
+        }
+        let secret_file = args
+            .secret_file
+            .as_deref()
+            .ok_or("--secret-file is required")?;
+        pseudonymiser = Some(Pseudonymiser::new(
+            secret_file,
+            &args.mapping_out,
+            args.dob_mode,
+        )?);
+        info!(
+            "Pseudonymising index, mapping table saved to: {}",
+            logging::path(&args.mapping_out)
+        );
+    }
 
     // Get crystal-eye path from environment variable or default to "./crystal-eye"
     let mut crystal_eye_path =
@@ -109,8 +646,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     check_crystal_eye_path(&mut crystal_eye_path);
 
     // Number of CPUs:
-    println!(
-        ">> Using {} of {} CPUs possible",
+    info!(
+        "Using {} of {} CPUs possible",
         num_jobs,
         system.cpus().len()
     );
@@ -120,102 +657,221 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut processed_file_paths = HashSet::new();
     if output_path.exists() && !overwrite {
-        processed_file_paths = read_existing_csv(&output_path)?;
+        let (existing_format, existing_header);
+        (processed_file_paths, existing_format, existing_header) = read_existing_csv(&output_path)?;
+        // A research-safe index lists file IDs, the mapping table gives their paths
+        if let Some(pseudonymiser) = &pseudonymiser {
+            let file_paths = pseudonymiser.file_paths(&processed_file_paths)?;
+            processed_file_paths.extend(file_paths);
+        }
+        let expected_header = match pseudonymiser.as_mut() {
+            Some(pseudonymiser) => {
+                csv_header(&pseudonymiser.apply(vec![DicomData::default()])?[0])?
+            }
+            None => csv_header(&DicomData::default())?,
+        };
+        // Columns changed since the CSV was written: start a new one, still skipping its files
+        if existing_header != expected_header {
+            warn!("Existing CSV has different columns, it will be moved aside");
+            handle_output_path(&output_path, false)?;
+            for table in ophthalmic::SIDE_TABLES {
+                handle_output_path(&ophthalmic::side_table_path(&output_path, table), false)?;
+            }
+        }
+        // Keep appending in the format the existing CSV was written with
+        if let Some(existing_format) = existing_format.filter(|f| *f != date_format) {
+            warn!(
+                "Existing CSV uses {} dates, appending in that format",
+                format!("{:?}", existing_format).to_lowercase()
+            );
+            date_format = existing_format;
+        }
     } else {
         handle_output_path(&output_path, overwrite)?;
+        for table in ophthalmic::SIDE_TABLES {
+            handle_output_path(&ophthalmic::side_table_path(&output_path, table), overwrite)?;
+        }
     }
 
     if let Ok(current_dir) = env::current_dir() {
         let full_path = current_dir.join(&output_path);
-        println!(">> Saving results to CSV file: {:?}", full_path);
+        info!("Saving results to CSV file: {}", logging::path(&full_path));
     } else {
-        println!("!! Error getting current working directory");
+        error!("Error getting current working directory");
     }
 
-    let mut timenow = Instant::now();
-    let mut counter = 0;
+    let options = ExtractOptions {
+        crystal_eye_path,
+        date_format,
+        image_type_rules,
+        check_dicomdir: args.check_dicomdir,
+        image_exports: args.image_exports,
+        filename_pattern,
+        path_patterns,
+        roots,
+    };
 
-    // Iterate over each matched folder and process the files
-    for folder_path in folder_paths {
-        let mut input_files: Vec<PathBuf> = Vec::new();
+    let mut batch = Batch {
+        input_files: Vec::new(),
+        archive_dir: None,
+        member_dirs: 0,
+        batch_size,
+        output_path: &output_path,
+        num_jobs,
+        options: &options,
+        processed_file_paths: &processed_file_paths,
+        exclusions: exclusions.as_ref(),
+        pseudonymiser: pseudonymiser.as_mut(),
+        counter: 0,
+        timenow: Instant::now(),
+    };
 
-        if folder_path.is_dir() {
-            println!(
-                ">> Walking directory and processing files in {:?}",
-                folder_path
-            );
-        }
+    let mut sources: Vec<Source> = folder_paths.into_iter().map(Source::Folder).collect();
+    if let Some(files_from) = &args.files_from {
+        let paths = read_file_list(files_from)?;
+        info!(
+            "Read {} paths to index from {}",
+            paths.len(),
+            logging::path(files_from)
+        );
+        sources.push(Source::FileList(paths));
+    }
 
-        for entry in WalkDir::new(&folder_path) {
+    // Iterate over each matched folder, then the listed files, and process the files
+    for source in &sources {
+        let entries: Box<dyn Iterator<Item = walkdir::Result<Entry>>> = match source {
+            Source::Folder(folder_path) => {
+                if folder_path.is_dir() {
+                    info!(
+                        "Walking directory and processing files in {}",
+                        logging::path(folder_path)
+                    );
+                }
+                Box::new(walk_entries(&walk_options, folder_path))
+            }
+            Source::FileList(paths) => Box::new(paths.iter().flat_map(|path| {
+                let entries: Box<dyn Iterator<Item = _>> = if path.is_dir() {
+                    Box::new(walk_entries(&walk_options, path))
+                } else if path.exists() {
+                    Box::new(std::iter::once(Ok(Entry {
+                        path: path.clone(),
+                        is_file: path.is_file(),
+                        listed: true,
+                    })))
+                } else {
+                    error!("Listed file not found: {}", logging::path(path));
+                    Box::new(std::iter::empty())
+                };
+                entries
+            })),
+        };
+
+        for entry in entries {
             match entry {
-                Ok(entry) => {
-                    if entry.path().extension().map_or(false, |ext| {
-                        CE_EXT
-                            .iter()
-                            .any(|ext_pattern| ext.eq_ignore_ascii_case(ext_pattern))
-                    }) {
-                        if entry
-                            .path()
-                            .metadata()
-                            .map_or(false, |meta| meta.len() == 0)
-                        {
-                            eprintln!("ERROR: Empty file: {:?}", entry.path());
+                Ok(Entry {
+                    path,
+                    is_file,
+                    listed,
+                }) => {
+                    if is_input_file(&path, options.image_exports) {
+                        if path.metadata().is_ok_and(|meta| meta.len() == 0) {
+                            error!("Empty file: {}", logging::path(&path));
                             continue;
                         }
-                        input_files.push(entry.path().to_path_buf());
-                        counter += 1;
-                    }
-
-                    if input_files.len() >= batch_size {
-                        if let Err(err) = process_and_save_results(
-                            &input_files,
-                            &output_path,
-                            num_jobs,
-                            &crystal_eye_path,
-                            &processed_file_paths,
-                        ) {
-                            eprintln!("ERROR: {:?}, reason: {:?}", &input_files, err);
+                        batch.push(InputFile {
+                            path,
+                            archive_path: None,
+                            dicomdir_record: None,
+                            listed: false,
+                        });
+                    } else if dicomdir::is_dicomdir(&path) {
+                        match dicomdir::read_records(&path) {
+                            Ok(records) => {
+                                // Files with an extension are picked up by the walk itself
+                                for record in records
+                                    .into_iter()
+                                    .filter(|r| !is_input_file(&r.path, options.image_exports))
+                                {
+                                    if !record.path.is_file() {
+                                        error!(
+                                            "File referenced by DICOMDIR not found: {}",
+                                            logging::path(&record.path)
+                                        );
+                                        continue;
+                                    }
+                                    batch.push(InputFile {
+                                        path: record.path.clone(),
+                                        archive_path: None,
+                                        dicomdir_record: Some(record),
+                                        listed: false,
+                                    });
+                                }
+                            }
+                            Err(err) => error!(
+                                "Error reading DICOMDIR {}: {}",
+                                logging::path(&path),
+                                logging::error_for(&err, &path)
+                            ),
                         }
-
-                        input_files.clear();
-                        print_speed(&timenow, batch_size as f32, counter);
-                        timenow = Instant::now();
+                    } else if is_file
+                        && path
+                            .file_name()
+                            .is_some_and(|name| archive::is_archive(&name.to_string_lossy()))
+                    {
+                        if let Err(err) = extract_archive(&path, &mut batch) {
+                            error!(
+                                "Error reading archive {}: {}",
+                                logging::path(&path),
+                                logging::error_for(&err, &path)
+                            );
+                        }
+                    } else if listed && is_file {
+                        // PACS exports often name DICOM files without an extension
+                        if path.metadata().is_ok_and(|meta| meta.len() == 0) {
+                            error!("Empty file: {}", logging::path(&path));
+                            continue;
+                        }
+                        batch.push(InputFile {
+                            path,
+                            archive_path: None,
+                            dicomdir_record: None,
+                            listed: true,
+                        });
                     }
                 }
                 Err(err) => {
                     // Handle the error, e.g., log the error and continue
-                    eprintln!("Error: {:?}", err);
+                    error!("{}", logging::walk_error(&err));
                 }
             }
         }
 
-        if !input_files.is_empty() {
-            if let Err(err) = process_and_save_results(
-                &input_files,
-                &output_path,
-                num_jobs,
-                &crystal_eye_path,
-                &processed_file_paths,
-            ) {
-                eprintln!("ERROR: {:?}, reason: {:?}", &input_files, err);
-            }
-            print_speed(&timenow, input_files.len() as f32, counter);
+        if !batch.input_files.is_empty() {
+            batch.process();
             println!()
         }
     }
+    let counter = batch.counter;
 
     if output_path.exists() {
-        println!(
-            ">> Results saved to {:?}",
-            output_path.canonicalize().unwrap()
+        info!(
+            "Results saved to {}",
+            logging::path(&output_path.canonicalize().unwrap())
         );
     } else {
-        println!(">> No data to save. Skipping CSV file creation.");
+        info!("No data to save. Skipping CSV file creation.");
+    }
+
+    if let Some(exclusions) = &exclusions {
+        info!(
+            "Excluded {} records of opted-out patients",
+            exclusions.excluded()
+        );
     }
 
     let tot_time = start_time.elapsed();
-    println!(
-        ">> processed: {} | Time elapsed: {:.2?} | Avg. speed: {:.2} it/s",
+    info!(
+        "processed: {} | Time elapsed: {:.2?} | Avg. speed: {:.2} it/s",
         counter,
         tot_time,
         counter as f32 / tot_time.as_secs_f32()
@@ -224,24 +880,64 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-fn read_existing_csv(csv_path: &Path) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+/// Header row a CSV of such rows gets
+fn csv_header<T: Serialize>(row: &T) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    wtr.serialize(row)?;
+    let data = wtr.into_inner().map_err(|e| e.to_string())?;
+    let mut rdr = csv::Reader::from_reader(data.as_slice());
+    Ok(rdr.headers()?.iter().map(str::to_string).collect())
+}
+
+/// Already processed file paths (file IDs for a research-safe index), the date format and the
+/// header the CSV was written with
+#[allow(clippy::type_complexity)]
+fn read_existing_csv(
+    csv_path: &Path,
+) -> Result<(HashSet<String>, Option<DateFormat>, Vec<String>), Box<dyn std::error::Error>> {
     let mut file_paths = HashSet::new();
+    let mut date_format = None;
 
     let file = File::open(csv_path)?;
     let mut rdr = csv::Reader::from_reader(BufReader::new(file));
 
-    for result in rdr.deserialize() {
-        let record: DicomData = result?;
-        file_paths.insert(record.file_path);
+    // Only `file_path` (or `file_id`) is needed, so research-safe indexes can be resumed too
+    let headers = rdr.headers()?.clone();
+    let file_path_idx = headers
+        .iter()
+        .position(|h| h == "file_path")
+        .or_else(|| headers.iter().position(|h| h == "file_id"))
+        .ok_or("CSV has no file_path or file_id column")?;
+    let date_idx: Vec<usize> = headers
+        .iter()
+        .enumerate()
+        .filter(|(_, h)| matches!(*h, "dob" | "scan_date" | "scan_datetime" | "modified"))
+        .map(|(i, _)| i)
+        .collect();
+    for result in rdr.records() {
+        let record = result?;
+        if let Some(file_path) = record.get(file_path_idx) {
+            file_paths.insert(file_path.to_string());
+        }
+        if date_format.is_none() {
+            date_format = date_idx
+                .iter()
+                .filter_map(|&i| record.get(i))
+                .find_map(DateFormat::detect);
+        }
     }
-    Ok(file_paths)
+    Ok((
+        file_paths,
+        date_format,
+        headers.iter().map(str::to_string).collect(),
+    ))
 }
 
 fn check_crystal_eye_path(crystal_eye_path: &mut String) {
     let path = Path::new(crystal_eye_path);
 
     if path.exists() {
-        println!(">> crystal-eye found at: {}", path.display());
+        info!("crystal-eye found at: {}", path.display());
         return;
     }
 
@@ -249,15 +945,15 @@ fn check_crystal_eye_path(crystal_eye_path: &mut String) {
         for path in std::env::split_paths(&path_var) {
             let full_path = path.join(&*crystal_eye_path);
             if full_path.exists() {
-                println!(">> crystal-eye found at: {}", full_path.display());
+                info!("crystal-eye found at: {}", full_path.display());
                 *crystal_eye_path = full_path.to_string_lossy().into_owned(); // Update the path
                 return;
             }
         }
     }
 
-    eprintln!(
-        ">> WARNING: crystal-eye not found at: {}\n   Only DICOM files will be processed, if any\n   Use 'export CRYSTAL_EYE_PATH=_path_to_crystal-eye_'",
+    warn!(
+        "crystal-eye not found at: {}\n   Only DICOM files will be processed, if any\n   Use 'export CRYSTAL_EYE_PATH=_path_to_crystal-eye_'",
         crystal_eye_path
     );
     *crystal_eye_path = String::new();
@@ -272,56 +968,214 @@ fn print_speed(start_time: &Instant, iterations: f32, counter: i32) {
     std::io::stdout().flush().unwrap();
 }
 
+/// Folders not below another one, nor given twice: files below a nested folder, such as a root
+/// inside a folder given positionally, would be indexed twice. Folders that cannot be
+/// canonicalised are kept, to be reported when walked.
+fn outermost_folders(folder_paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let canonical: Vec<Option<PathBuf>> = folder_paths
+        .iter()
+        .map(|path| path.canonicalize().ok())
+        .collect();
+    folder_paths
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| {
+            let Some(dir) = &canonical[*i] else {
+                return true;
+            };
+            !canonical.iter().enumerate().any(|(j, other)| {
+                other.as_ref().is_some_and(|other| {
+                    if dir == other {
+                        j < *i
+                    } else {
+                        dir.starts_with(other)
+                    }
+                })
+            })
+        })
+        .map(|(_, path)| path)
+        .collect()
+}
+
+/// Files waiting to be processed, processed and saved every `batch_size` files
+struct Batch<'a> {
+    input_files: Vec<InputFile>,
+    /// Archive members of the batch are extracted here, removed once it is processed
+    archive_dir: Option<TempDir>,
+    /// Directories made in `archive_dir`, members that failed included
+    member_dirs: usize,
+    batch_size: usize,
+    output_path: &'a Path,
+    num_jobs: usize,
+    options: &'a ExtractOptions,
+    processed_file_paths: &'a HashSet<String>,
+    exclusions: Option<&'a PatientExclusions>,
+    pseudonymiser: Option<&'a mut Pseudonymiser>,
+    /// Files found so far
+    counter: i32,
+    timenow: Instant,
+}
+
+impl Batch<'_> {
+    /// Add a file, processing the batch once it is full
+    fn push(&mut self, input_file: InputFile) {
+        self.input_files.push(input_file);
+        self.counter += 1;
+        if self.input_files.len() >= self.batch_size {
+            self.process();
+        }
+    }
+
+    /// New directory to extract an archive member of the batch to, one per member as names
+    /// may repeat across folders of an archive
+    fn member_dir(&mut self) -> io::Result<PathBuf> {
+        let archive_dir = match self.archive_dir.take() {
+            Some(archive_dir) => archive_dir,
+            None => tempdir()?,
+        };
+        let member_dir = archive_dir.path().join(self.member_dirs.to_string());
+        self.archive_dir = Some(archive_dir);
+        self.member_dirs += 1;
+        fs::create_dir(&member_dir)?;
+        Ok(member_dir)
+    }
+
+    fn process(&mut self) {
+        if let Err(err) = process_and_save_results(
+            &self.input_files,
+            self.output_path,
+            self.num_jobs,
+            self.options,
+            self.processed_file_paths,
+            self.exclusions,
+            self.pseudonymiser.as_deref_mut(),
+        ) {
+            error!("{}, reason: {:?}", logging::paths(&self.input_files), err);
+        }
+
+        print_speed(&self.timenow, self.input_files.len() as f32, self.counter);
+        self.input_files.clear();
+        self.archive_dir = None;
+        self.member_dirs = 0;
+        self.timenow = Instant::now();
+    }
+}
+
+/// Extract the members to index of an archive, and of the archives nested in it, that are not
+/// indexed yet, adding them to the batch one by one: only the members of one batch are on
+/// disk at a time, whatever the size of the archive
+fn extract_archive(path: &Path, batch: &mut Batch) -> io::Result<()> {
+    let archive_path = path.canonicalize()?.to_string_lossy().to_string();
+    let options = batch.options;
+    let existing_paths = batch.processed_file_paths;
+    let wanted = |member: &str| {
+        archive::is_archive(member)
+            || (is_input_file(Path::new(member), options.image_exports)
+                && !existing_paths.contains(member))
+    };
+    archive::visit_members(
+        path,
+        &archive_path,
+        &wanted,
+        &mut |member, reader, modified| {
+            // `a/..` names no file
+            let name = Path::new(member).file_name().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "member without a file name")
+            })?;
+            let member_path = batch.member_dir()?.join(name);
+            let mut file = File::create(&member_path)?;
+            io::copy(reader, &mut file)?;
+            if let Some(modified) = modified {
+                file.set_modified(modified)?;
+            }
+            batch.push(InputFile {
+                path: member_path,
+                archive_path: Some(member.to_string()),
+                dicomdir_record: None,
+                listed: false,
+            });
+            Ok(())
+        },
+        &mut |member, err| {
+            error!(
+                "Error reading archive member {}: {}",
+                logging::path(Path::new(member)),
+                logging::error_for(&err, Path::new(member))
+            )
+        },
+    )
+}
+
 fn process_and_save_results(
-    input_files: &[PathBuf],
+    input_files: &[InputFile],
     output_path: &Path,
     num_jobs: usize,
-    crystal_eye_path: &str,
+    options: &ExtractOptions,
     processed_file_paths: &HashSet<String>,
+    exclusions: Option<&PatientExclusions>,
+    pseudonymiser: Option<&mut Pseudonymiser>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Process DICOM files in parallel
-    let results: Vec<_> = input_files
+    let mut results: Vec<_> = input_files
         .par_chunks(num_jobs)
-        .map(|chunk| process_input_files(chunk, crystal_eye_path, processed_file_paths))
+        .map(|chunk| process_input_files(chunk, options, processed_file_paths))
         .flatten()
         .collect();
 
+    // Drop opted-out patients before anything is written, including the pseudonym mapping
+    if let Some(exclusions) = exclusions {
+        let before = results.len();
+        results.retain(|data| {
+            !data
+                .patient_ids()
+                .iter()
+                .any(|patient_id| exclusions.contains(patient_id))
+        });
+        exclusions.record_excluded(before - results.len());
+    }
+
+    // Visual field and biometry measurements go to their own tables, linked by file_path
+    if let Some(pseudonymiser) = pseudonymiser.as_deref() {
+        pseudonymiser.apply_side_data(&mut results);
+    }
+    let (visual_fields, axial_measurements) = ophthalmic::take_side_data(&mut results);
+    if !visual_fields.is_empty() {
+        save_results_to_csv(
+            &visual_fields,
+            &ophthalmic::side_table_path(output_path, ophthalmic::VISUAL_FIELDS_TABLE),
+        )?;
+    }
+    if !axial_measurements.is_empty() {
+        save_results_to_csv(
+            &axial_measurements,
+            &ophthalmic::side_table_path(output_path, ophthalmic::AXIAL_MEASUREMENTS_TABLE),
+        )?;
+    }
+
     if !results.is_empty() {
-        save_results_to_csv(&results, output_path)?;
+        match pseudonymiser {
+            Some(pseudonymiser) => {
+                save_results_to_csv(&pseudonymiser.apply(results)?, output_path)?
+            }
+            None => save_results_to_csv(&results, output_path)?,
+        }
     }
 
     Ok(())
 }
 
-fn save_results_to_csv(
-    results: &[DicomData],
+fn save_results_to_csv<T: Serialize>(
+    results: &[T],
     output_path: &Path,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let file: File = OpenOptions::new()
         .create(true)
         .append(true)
         .open(output_path)?;
+    // Write the header (taken from the row struct) only if the file is empty
     let mut wtr = csv::WriterBuilder::new()
-        .has_headers(false)
+        .has_headers(output_path.metadata()?.len() == 0)
         .from_writer(file);
-    // Check if the file is empty (has no content) before writing the header
-    if output_path.metadata()?.len() == 0 {
-        // Write the header only if the file is empty
-        wtr.write_record([
-            "patient_id",
-            "patient_name",
-            "laterality",
-            "sex",
-            "dob",
-            "scan_date",
-            "modality",
-            "manufacturer",
-            "series_description",
-            "modified",
-            "file_size",
-            "file_path",
-        ])?;
-    }
     for result in results {
         wtr.serialize(result)?;
     }
@@ -330,58 +1184,129 @@ fn save_results_to_csv(
 }
 
 fn process_input_files(
-    paths: &[PathBuf],
-    crystal_eye_path: &str,
+    input_files: &[InputFile],
+    options: &ExtractOptions,
     existing_paths: &HashSet<String>,
 ) -> Vec<DicomData> {
-    paths
+    input_files
         .iter()
-        .filter(|path| path.metadata().ok().map_or(false, |m| m.len() > 0))
-        .filter_map(|path| {
-            let absolute_path = match path.canonicalize() {
-                Ok(abs_path) => abs_path,
-                Err(e) => {
-                    eprintln!("Error obtaining canonical path for {:?}: {}", path, e);
-                    return None;
-                }
-            };
-            if existing_paths.contains(absolute_path.to_str().unwrap_or_default()) {
-                return None; // Skip already processed files
+        .filter(|input| input.path.metadata().is_ok_and(|m| m.len() > 0))
+        .filter_map(|input| {
+            let mut data = extract_input_file(input, options, existing_paths)?;
+            if let Some(archive_path) = &input.archive_path {
+                data.set_file_path(archive_path);
             }
-            if let Some(ext) = path.extension() {
-                if ext.eq_ignore_ascii_case("dcm") {
-                    match extract_dicom_data_with_retry(path, 10) {
-                        Ok(data) => Some(data),
-                        Err(e) => {
-                            eprintln!("Error processing DCM input file {:?}: {}", path, e);
-                            None
-                        }
-                    }
-                } else if CE_EXT
-                    .iter()
-                    .any(|ext_pattern| ext.eq_ignore_ascii_case(ext_pattern))
-                    && !crystal_eye_path.is_empty()
-                {
-                    match extract_crystal_eye_data(path, crystal_eye_path) {
-                        Ok(data) => Some(data),
-                        Err(e) => {
-                            eprintln!("Error processing crystal-eye input file {:?}: {}", path, e);
-                            None
-                        }
-                    }
-                } else {
-                    None // Skip files with other extensions
+            if let Some(record) = &input.dicomdir_record {
+                // The header comes first, the DICOMDIR fills what it lacks
+                data.dicomdir_patient_id = record.patient_id.clone();
+                if data.patient_id.is_empty() {
+                    data.patient_id = record.patient_id.clone();
+                }
+                if data.modality.is_empty() {
+                    data.modality = record.modality.clone();
                 }
-            } else {
-                None // Skip files without extensions
             }
+            if !options.roots.is_empty() {
+                data.set_root(&options.roots);
+            }
+            if !options.path_patterns.is_empty() {
+                data.set_path_fields(&options.path_patterns, options.date_format);
+            }
+            Some(data)
         })
         .collect()
 }
 
+fn extract_input_file(
+    input: &InputFile,
+    options: &ExtractOptions,
+    existing_paths: &HashSet<String>,
+) -> Option<DicomData> {
+    let path = &input.path;
+    if input.archive_path.is_none() {
+        let absolute_path = match path.canonicalize() {
+            Ok(abs_path) => abs_path,
+            Err(e) => {
+                error!(
+                    "Error obtaining canonical path for {}: {}",
+                    logging::path(path),
+                    logging::error_for(&e, path)
+                );
+                return None;
+            }
+        };
+        if existing_paths.contains(absolute_path.to_str().unwrap_or_default()) {
+            return None; // Skip already processed files
+        }
+    }
+    if let Some(record) = input
+        .dicomdir_record
+        .as_ref()
+        .filter(|_| options.check_dicomdir)
+    {
+        match dicomdir::check_record(record) {
+            Ok(differences) if !differences.is_empty() => warn!(
+                "DICOMDIR record of {} differs from its header: {}",
+                logging::path(path),
+                differences.join(", ")
+            ),
+            Ok(_) => {}
+            Err(e) => error!(
+                "Error checking DICOMDIR record of {}: {}",
+                logging::path(path),
+                logging::error_for(&e, path)
+            ),
+        }
+    }
+    let is_dicom = input.dicomdir_record.is_some()
+        || input.listed
+        || compression::uncompressed_path(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("dcm"));
+    if is_dicom {
+        match extract_dicom_data_with_retry(path, options, 10) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                error!(
+                    "Error processing DCM input file {}: {}",
+                    logging::path(input.as_ref()),
+                    logging::error_for(&e, path)
+                );
+                None
+            }
+        }
+    } else if has_ce_ext(path) && !options.crystal_eye_path.is_empty() {
+        match extract_crystal_eye_data(path, options) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                error!(
+                    "Error processing crystal-eye input file {}: {}",
+                    logging::path(input.as_ref()),
+                    logging::error_for(&e, path)
+                );
+                None
+            }
+        }
+    } else if options.image_exports && image_export::source_format(path).is_some() {
+        match extract_image_export_data(path, options) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                error!(
+                    "Error processing image export {}: {}",
+                    logging::path(input.as_ref()),
+                    logging::error_for(&e, path)
+                );
+                None
+            }
+        }
+    } else {
+        None // Skip files with other extensions, or none
+    }
+}
+
 fn extract_crystal_eye_data(
     path: &Path,
-    crystal_eye_path: &str,
+    options: &ExtractOptions,
 ) -> Result<DicomData, Box<dyn std::error::Error>> {
     let temp_dir = tempdir()?;
     let output_dir = temp_dir.path();
@@ -390,7 +1315,7 @@ fn extract_crystal_eye_data(
     let path_str = path.to_string_lossy().replace('\\', "/");
 
     // Run crystal-eye command
-    let output = Command::new(crystal_eye_path)
+    let output = Command::new(&options.crystal_eye_path)
         .arg("-i")
         .arg(&path_str)
         .arg("--only-metadata")
@@ -408,20 +1333,31 @@ fn extract_crystal_eye_data(
     let metadata: CEMetadata = serde_json::from_reader(metadata_file)?;
 
     // Use unwrap_or("") to handle null values and replace them with empty strings.
-    let patient_name = format!(
-        "{} {}",
-        metadata.patient.first_name.unwrap_or_default(),
-        metadata.patient.last_name.unwrap_or_default()
+    let patient_name = PersonName::from_first_last(
+        &metadata.patient.first_name.unwrap_or_default(),
+        &metadata.patient.last_name.unwrap_or_default(),
     );
 
     let formatted_patient_dob = format_date(
         &metadata.patient.date_of_birth.unwrap_or_default(),
         Some("%Y-%m-%d"),
+        options.date_format,
     );
+    let scan_datetime = metadata.exam.scan_datetime.unwrap_or_default();
+    let manufacturer = metadata.exam.manufacturer.unwrap_or_default();
     let formatted_content_date = format_date(
-        &metadata.exam.scan_datetime.unwrap_or_default(),
+        &scan_datetime,
         Some("%Y-%m-%d %H:%M:%S%.f"),
+        options.date_format,
     );
+    let (scan_datetime, scan_datetime_source) =
+        match NaiveDateTime::parse_from_str(&scan_datetime, "%Y-%m-%d %H:%M:%S%.f") {
+            Ok(datetime) => (
+                options.date_format.format_datetime(datetime),
+                "crystal-eye".to_string(),
+            ),
+            Err(_) => (String::new(), String::new()),
+        };
 
     let file_path = path
         .canonicalize()?
@@ -430,35 +1366,169 @@ fn extract_crystal_eye_data(
         .to_string();
 
     let ce_file = fs::metadata(path)?;
-    let modified = format_modified_datetime(ce_file.modified());
+    let modified = format_modified_datetime(ce_file.modified(), options.date_format);
     let file_size = ce_file.len();
 
-    Ok(DicomData {
+    let protocol = metadata.series.protocol.unwrap_or_default();
+    let ce_laterality = metadata.series.laterality.unwrap_or_default();
+    let (laterality, laterality_source) = match laterality::normalise(&ce_laterality) {
+        Some(laterality) => (laterality, "crystal-eye"),
+        None => match laterality::from_description(&protocol) {
+            Some(laterality) => (laterality, "crystal-eye protocol"),
+            None => (laterality::UNKNOWN, ""),
+        },
+    };
+    let image_type = options.image_type_rules.classify(&ImageTypeInputs {
+        modality: "CE",
+        series_description: &protocol,
+        protocol: &protocol,
+        ..Default::default()
+    });
+
+    let mut data = DicomData {
         patient_id: metadata.patient.patient_key.unwrap_or_default(),
-        patient_name,
-        laterality: metadata.series.laterality.unwrap_or_default(),
+        laterality: laterality.to_string(),
+        laterality_source: laterality_source.to_string(),
         sex: metadata.patient.gender.unwrap_or_default(),
         dob: formatted_patient_dob,
         scan_date: formatted_content_date,
+        scan_datetime,
+        scan_datetime_source,
+        source_format: path
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase(),
         modality: "CE".to_string(),
-        manufacturer: metadata.exam.manufacturer.unwrap_or_default(),
-        series_description: metadata.series.protocol.unwrap_or_default(),
+        image_type,
+        manufacturer_normalised: manufacturer::normalise(&manufacturer),
+        manufacturer,
+        manufacturer_model_name: metadata.exam.manufacturer_model.unwrap_or_default(),
+        device_serial_number: metadata.exam.scanner_serial_number.unwrap_or_default(),
+        software_versions: metadata.exam.scanner_software_version.unwrap_or_default(),
+        station_name: metadata.exam.station_name.unwrap_or_default(),
+        institution_name: metadata.exam.institution_name.unwrap_or_default(),
+        series_description: protocol,
         modified,
         file_size,
         file_path,
-    })
+        ..Default::default()
+    };
+    data.set_patient_name(patient_name);
+    data.set_age_and_date_flags(false);
+
+    Ok(data)
+}
+
+/// Row of a JPEG/PNG/TIFF export, taken as ophthalmic photography unless its sidecar says otherwise
+fn extract_image_export_data(
+    path: &Path,
+    options: &ExtractOptions,
+) -> Result<DicomData, Box<dyn std::error::Error>> {
+    let metadata = image_export::read_metadata(path, options.filename_pattern.as_ref())?;
+
+    let patient_name = match metadata.value("patient_name") {
+        "" => {
+            PersonName::from_first_last(metadata.value("first_name"), metadata.value("last_name"))
+        }
+        name if name.contains('^') => PersonName::parse(name),
+        name => PersonName::from_first_last(
+            name.split_whitespace().next().unwrap_or_default(),
+            &name
+                .split_whitespace()
+                .skip(1)
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+    };
+    let dob = parse_loose_date(metadata.value("dob"))
+        .map(|date| options.date_format.format(date))
+        .unwrap_or_default();
+    let date = metadata.value("scan_date");
+    let scan_date = parse_loose_date(date)
+        .map(|date| options.date_format.format(date))
+        .unwrap_or_default();
+    let scan_datetime = parse_loose_datetime(date)
+        .or_else(|| parse_loose_date(date).and_then(|date| date.and_hms_opt(0, 0, 0)));
+    let (scan_datetime, scan_datetime_source) = match scan_datetime {
+        Some(datetime) => (
+            options.date_format.format_datetime(datetime),
+            metadata.source("scan_date").to_string(),
+        ),
+        None => (String::new(), String::new()),
+    };
+    let (laterality, laterality_source) = match laterality::normalise(metadata.value("laterality"))
+    {
+        Some(laterality) => (laterality, metadata.source("laterality")),
+        None => (laterality::UNKNOWN, ""),
+    };
+
+    let modality = match metadata.value("modality") {
+        "" => "OP",
+        modality => modality,
+    };
+    let description = metadata.value("description");
+    let image_type = options.image_type_rules.classify(&ImageTypeInputs {
+        modality,
+        series_description: description,
+        protocol: description,
+        ..Default::default()
+    });
+
+    let file_path = path
+        .canonicalize()?
+        .to_str()
+        .ok_or("Invalid file path")?
+        .to_string();
+    let file_metadata = fs::metadata(path)?;
+    let manufacturer = metadata.value("manufacturer").to_string();
+
+    let mut data = DicomData {
+        patient_id: metadata.value("patient_id").to_string(),
+        laterality: laterality.to_string(),
+        laterality_source: laterality_source.to_string(),
+        sex: metadata.value("sex").to_string(),
+        dob,
+        scan_date,
+        scan_datetime,
+        scan_datetime_source,
+        source_format: image_export::source_format(path)
+            .unwrap_or_default()
+            .to_string(),
+        modality: modality.to_string(),
+        image_type,
+        manufacturer_normalised: manufacturer::normalise(&manufacturer),
+        manufacturer,
+        manufacturer_model_name: metadata.value("model").to_string(),
+        device_serial_number: metadata.value("serial_number").to_string(),
+        software_versions: metadata.value("software").to_string(),
+        series_description: description.to_string(),
+        modified: format_modified_datetime(file_metadata.modified(), options.date_format),
+        file_size: file_metadata.len(),
+        file_path,
+        ..Default::default()
+    };
+    data.set_patient_name(patient_name);
+    data.set_age_and_date_flags(false);
+
+    Ok(data)
 }
 
 fn extract_dicom_data_with_retry(
     path: &Path,
+    options: &ExtractOptions,
     max_retries: u64,
 ) -> Result<DicomData, Box<dyn std::error::Error>> {
     let mut retries = 0;
     loop {
-        match extract_dicom_data(path) {
+        match extract_dicom_data(path, options) {
             Ok(data) => return Ok(data),
             Err(e) if retries < max_retries => {
-                eprintln!("Error processing {:?}: {}. Retrying...", path, e);
+                warn!(
+                    "Error processing {}: {}. Retrying...",
+                    logging::path(path),
+                    logging::error_for(&e, path)
+                );
                 retries += 1;
                 thread::sleep(Duration::from_millis(500 * retries));
             }
@@ -467,61 +1537,66 @@ fn extract_dicom_data_with_retry(
     }
 }
 
-fn extract_dicom_data(path: &Path) -> Result<DicomData, Box<dyn std::error::Error>> {
-    let obj = OpenFileOptions::new()
-        .read_until(tags::PIXEL_DATA)
-        .open_file(path)?;
+/// Open a DICOM file up to its pixel data, decompressing `.dcm.gz`/`.dcm.zst` files as they are read
+fn open_dicom(path: &Path) -> Result<DefaultDicomObject, Box<dyn std::error::Error>> {
+    let options = OpenFileOptions::new().read_until(tags::PIXEL_DATA);
+    if !compression::is_compressed_dicom(path) {
+        return Ok(options.open_file(path)?);
+    }
+    // The stream cannot be rewound to detect the preamble, so it is read ahead
+    let mut reader = compression::open(path)?;
+    let mut head = [0u8; 132];
+    reader.read_exact(&mut head)?;
+    let start = if &head[128..] == b"DICM" { 128 } else { 0 };
+    Ok(options
+        .read_preamble(ReadPreamble::Never)
+        .from_reader((&head[start..]).chain(reader))?)
+}
 
-    let patient_id = if let Some(elem) = obj.element_opt(tags::PATIENT_ID)? {
-        elem.to_str()?
-    } else {
-        "".into()
-    };
-    let patient_name = if let Some(elem) = obj.element_opt(tags::PATIENT_NAME)? {
-        elem.to_str()?
-    } else {
-        "".into()
-    };
-    let image_laterality = if let Some(elem) = obj.element_opt(tags::IMAGE_LATERALITY)? {
-        elem.to_str()?
-    } else if let Some(elem) = obj.element_opt(tags::LATERALITY)? {
-        elem.to_str()?
-    } else {
-        "".into()
-    };
-    let patient_sex = if let Some(elem) = obj.element_opt(tags::PATIENT_SEX)? {
-        elem.to_str()?
-    } else {
-        "".into()
-    };
-    let patient_dob = if let Some(elem) = obj.element_opt(tags::PATIENT_BIRTH_DATE)? {
-        elem.to_str()?
-    } else {
-        "".into()
-    };
-    let content_date = if let Some(elem) = obj.element_opt(tags::CONTENT_DATE)? {
-        elem.to_str()?
-    } else {
-        "".into()
-    };
-    let modality = if let Some(elem) = obj.element_opt(tags::MODALITY)? {
-        elem.to_str()?
-    } else {
-        "".into()
-    };
-    let manufacturer = if let Some(elem) = obj.element_opt(tags::MANUFACTURER)? {
-        elem.to_str()?
-    } else {
-        "".into()
+fn extract_dicom_data(
+    path: &Path,
+    options: &ExtractOptions,
+) -> Result<DicomData, Box<dyn std::error::Error>> {
+    let obj = open_dicom(path)?;
+
+    let patient_id = element_str(&obj, tags::PATIENT_ID)?;
+    let patient_name = person_name::decoded_person_name(&obj, tags::PATIENT_NAME)?;
+    let (laterality, laterality_source) = laterality::dicom_laterality(&obj)?;
+    let patient_sex = element_str(&obj, tags::PATIENT_SEX)?;
+    let patient_dob = element_str(&obj, tags::PATIENT_BIRTH_DATE)?;
+    let content_date = element_str(&obj, tags::CONTENT_DATE)?;
+    let modality = element_str(&obj, tags::MODALITY)?;
+    let manufacturer = element_str(&obj, tags::MANUFACTURER)?;
+    let manufacturer_model_name = element_str(&obj, tags::MANUFACTURER_MODEL_NAME)?;
+    let device_serial_number = element_str(&obj, tags::DEVICE_SERIAL_NUMBER)?;
+    // Multi-valued, one per component, kept `\`-separated
+    let software_versions = element_str(&obj, tags::SOFTWARE_VERSIONS)?;
+    let station_name = element_str(&obj, tags::STATION_NAME)?;
+    let institution_name = element_str(&obj, tags::INSTITUTION_NAME)?;
+    let series_description = element_str(&obj, tags::SERIES_DESCRIPTION)?;
+    let sop_class_uid = match element_str(&obj, tags::SOP_CLASS_UID)? {
+        uid if !uid.is_empty() => uid,
+        _ => obj.meta().media_storage_sop_class_uid().to_string(),
     };
-    let series_description = if let Some(elem) = obj.element_opt(tags::SERIES_DESCRIPTION)? {
-        elem.to_str()?
-    } else {
-        "".into()
+    let sop_instance_uid = element_str(&obj, tags::SOP_INSTANCE_UID)?;
+    let scan_timestamp = scan_timestamp(&obj)?;
+
+    let formatted_patient_dob = format_date(&patient_dob, None, options.date_format);
+    // Without ContentDate, fall back on the date of the scan timestamp
+    let formatted_content_date = match format_date(&content_date, None, options.date_format) {
+        date if date.is_empty() => scan_timestamp
+            .map(|(datetime, _)| options.date_format.format(datetime.date()))
+            .unwrap_or_default(),
+        date => date,
     };
-
-    let formatted_patient_dob = format_date(&patient_dob, None);
-    let formatted_content_date = format_date(&content_date, None);
+    let (scan_datetime, scan_datetime_source) = scan_timestamp
+        .map(|(datetime, source)| {
+            (
+                options.date_format.format_datetime(datetime),
+                source.to_string(),
+            )
+        })
+        .unwrap_or_default();
 
     // let file_path = path::absolute(path)?.to_string_lossy().to_string();
     let file_path = path
@@ -532,64 +1607,106 @@ fn extract_dicom_data(path: &Path) -> Result<DicomData, Box<dyn std::error::Erro
 
     let metadata = fs::metadata(path)?;
 
-    let modified = format_modified_datetime(metadata.modified());
+    let modified = format_modified_datetime(metadata.modified(), options.date_format);
     let file_size = metadata.len();
 
-    // patient_id,patient_name,laterality,sex,dob,scan_date,modality,manufacturer,series_description,modified,file_size,file_path
-    Ok(DicomData {
-        patient_id: patient_id.to_string(),
-        patient_name: patient_name.to_string(),
-        laterality: image_laterality.to_string(),
-        sex: patient_sex.to_string(),
+    let mut data = DicomData {
+        patient_id,
+        laterality: laterality.to_string(),
+        laterality_source: laterality_source.to_string(),
+        sex: patient_sex,
         dob: formatted_patient_dob,
         scan_date: formatted_content_date,
-        modality: modality.to_string(),
-        manufacturer: manufacturer.to_string(),
-        series_description: series_description.to_string(),
+        scan_datetime,
+        scan_datetime_source,
+        source_format: "dicom".to_string(),
+        modality,
+        manufacturer_normalised: manufacturer::normalise(&manufacturer),
+        manufacturer,
+        manufacturer_model_name,
+        device_serial_number,
+        software_versions,
+        station_name,
+        institution_name,
+        series_description,
+        sop_class_uid,
+        sop_instance_uid,
         modified,
         file_size,
         file_path,
-    })
+        ..Default::default()
+    };
+    data.set_patient_name(PersonName::parse(&patient_name));
+    // Six-digit DOBs get their century guessed by `format_date`
+    data.set_age_and_date_flags(patient_dob.trim().len() == 6);
+    ophthalmic::fill_opt_metadata(&obj, &mut data)?;
+    data.side_data = ophthalmic::side_data(&obj, &data)?;
+    data.image_type = options.image_type_rules.classify(&ImageTypeInputs {
+        sop_class_uid: &data.sop_class_uid,
+        modality: &data.modality,
+        dicom_image_type: &element_str(&obj, tags::IMAGE_TYPE)?,
+        series_description: &data.series_description,
+        protocol: &element_str(&obj, tags::PROTOCOL_NAME)?,
+        acquisition: &ophthalmic::acquisition_description(&obj)?,
+    });
+
+    Ok(data)
 }
 
-fn format_modified_datetime(modified: io::Result<SystemTime>) -> String {
-    match modified {
-        Ok(time) => {
-            // Convert SystemTime to DateTime<Local>
-            let datetime: DateTime<Local> = time.into();
-            // Format the datetime with the specified format
-            datetime.format("%d-%m-%Y %H:%M:%S").to_string()
-        }
-        Err(_) => {
-            // Return an empty string if there's an error
-            "".to_string()
-        }
+/// String value of an element, empty if absent
+pub fn element_str(obj: &InMemDicomObject, tag: Tag) -> Result<String, Box<dyn std::error::Error>> {
+    match obj.element_opt(tag)? {
+        // UI values are padded with NULs
+        Some(elem) => Ok(elem.to_str()?.trim_end_matches('\0').to_string()),
+        None => Ok(String::new()),
     }
 }
 
-fn format_date(date_str: &str, format_str: Option<&str>) -> String {
-    let default_format = "%Y%m%d";
-    let format_to_use = format_str.unwrap_or(default_format);
-
-    if let Ok(parsed_date) = NaiveDate::parse_from_str(date_str, format_to_use) {
-        parsed_date.format("%d-%m-%Y").to_string()
-    } else if format_str.is_none() {
-        // Attempt to handle ambiguous dates
-        attempt_ambiguous_date_parse(date_str)
-    } else {
-        String::new()
+/// Scan timestamp and the attributes it was built from, most specific first.
+/// Date/time pairs with both values are preferred over a date alone.
+fn scan_timestamp(
+    obj: &InMemDicomObject,
+) -> Result<Option<(NaiveDateTime, &'static str)>, Box<dyn std::error::Error>> {
+    if let Some(datetime) = parse_dicom_datetime(&element_str(obj, tags::ACQUISITION_DATE_TIME)?) {
+        return Ok(Some((datetime, "AcquisitionDateTime")));
     }
-}
 
-fn attempt_ambiguous_date_parse(date_str: &str) -> String {
-    // Try parsing assuming no century (e.g., "010180" becomes "1980-01-01")
-    if let Ok(parsed_date) = NaiveDate::parse_from_str(date_str, "%y%m%d") {
-        // Check if the parsed year is within a reasonable range
-        if parsed_date.year() > 1900 {
-            // Adjust threshold as needed
-            return parsed_date.format("%d-%m-%Y").to_string();
+    let pairs = [
+        (
+            tags::ACQUISITION_DATE,
+            tags::ACQUISITION_TIME,
+            "AcquisitionDate+AcquisitionTime",
+            "AcquisitionDate",
+        ),
+        (
+            tags::CONTENT_DATE,
+            tags::CONTENT_TIME,
+            "ContentDate+ContentTime",
+            "ContentDate",
+        ),
+        (
+            tags::SERIES_DATE,
+            tags::SERIES_TIME,
+            "SeriesDate+SeriesTime",
+            "SeriesDate",
+        ),
+        (
+            tags::STUDY_DATE,
+            tags::STUDY_TIME,
+            "StudyDate+StudyTime",
+            "StudyDate",
+        ),
+    ];
+    let mut date_only = None;
+    for (date_tag, time_tag, source, date_source) in pairs {
+        let date = element_str(obj, date_tag)?;
+        let time = element_str(obj, time_tag)?;
+        if let Some(datetime) = parse_dicom_date_time(&date, &time) {
+            if parse_dicom_time(&time).is_some() {
+                return Ok(Some((datetime, source)));
+            }
+            date_only = date_only.or(Some((datetime, date_source)));
         }
     }
-
-    String::new() // Return empty if still unsuccessful
+    Ok(date_only)
 }
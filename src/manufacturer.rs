@@ -0,0 +1,57 @@
+pub const HEIDELBERG_ENGINEERING: &str = "Heidelberg Engineering";
+
+/// Canonical names of the usual ophthalmic device manufacturers, keyed by the start of the
+/// lowercased name with punctuation and legal suffixes removed
+const KNOWN_MANUFACTURERS: &[(&str, &str)] = &[
+    ("heidelberg", HEIDELBERG_ENGINEERING),
+    ("carl zeiss", "Carl Zeiss Meditec"),
+    ("zeiss", "Carl Zeiss Meditec"),
+    ("topcon", "Topcon"),
+    ("optos", "Optos"),
+    ("optovue", "Optovue"),
+    ("nidek", "Nidek"),
+    ("canon", "Canon"),
+    ("kowa", "Kowa"),
+    ("centervue", "CenterVue"),
+    ("haag streit", "Haag-Streit"),
+    ("tomey", "Tomey"),
+];
+
+/// Legal form suffixes dropped before matching
+const LEGAL_SUFFIXES: &[&str] = &[
+    "gmbh",
+    "ag",
+    "inc",
+    "ltd",
+    "limited",
+    "plc",
+    "llc",
+    "co",
+    "corp",
+    "corporation",
+    "sa",
+    "bv",
+    "kk",
+];
+
+/// Normalised manufacturer name, e.g. `HEIDELBERG ENGINEERING GmbH` gives `Heidelberg Engineering`.
+/// Unknown manufacturers keep their name, without legal form suffix and extra spaces.
+pub fn normalise(manufacturer: &str) -> String {
+    let mut words: Vec<&str> = manufacturer
+        .split(|c: char| c.is_whitespace() || c == ',' || c == '.')
+        .filter(|w| !w.is_empty())
+        .collect();
+    while words
+        .last()
+        .is_some_and(|w| LEGAL_SUFFIXES.contains(&w.to_lowercase().as_str()))
+    {
+        words.pop();
+    }
+
+    let key = words.join(" ").to_lowercase().replace('-', " ");
+    KNOWN_MANUFACTURERS
+        .iter()
+        .find(|(prefix, _)| key.starts_with(prefix))
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| words.join(" "))
+}
@@ -0,0 +1,448 @@
+use dicom_dictionary_std::{tags, uids};
+use dicom_object::{InMemDicomObject, Tag};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use crate::{element_str, DicomData};
+
+/// Value of `tag` in the first item of sequence `seq`, empty if absent
+fn item_str(
+    obj: &InMemDicomObject,
+    seq: Tag,
+    tag: Tag,
+) -> Result<String, Box<dyn std::error::Error>> {
+    match obj.element_opt(seq)?.and_then(|elem| elem.items()?.first()) {
+        Some(item) => element_str(item, tag),
+        None => Ok(String::new()),
+    }
+}
+
+/// Value of `tag` in the Pixel Measures functional group, shared by all frames or else of the
+/// first frame, empty if absent; where multi-frame images such as Ophthalmic Tomography keep
+/// their geometry
+fn pixel_measure(obj: &InMemDicomObject, tag: Tag) -> Result<String, Box<dyn std::error::Error>> {
+    for groups in [
+        tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE,
+        tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+    ] {
+        if let Some(group) = obj
+            .element_opt(groups)?
+            .and_then(|elem| elem.items()?.first())
+        {
+            let value = item_str(group, tags::PIXEL_MEASURES_SEQUENCE, tag)?;
+            if !value.is_empty() {
+                return Ok(value);
+            }
+        }
+    }
+    Ok(String::new())
+}
+
+/// Refractive state as `S:<sphere> C:<cylinder> A:<axis>`, empty parts left out
+fn refractive_state(obj: &InMemDicomObject) -> Result<String, Box<dyn std::error::Error>> {
+    let parts = [
+        ("S", tags::SPHERICAL_LENS_POWER),
+        ("C", tags::CYLINDER_LENS_POWER),
+        ("A", tags::CYLINDER_AXIS),
+    ];
+    let mut values = Vec::new();
+    for (label, tag) in parts {
+        let value = item_str(obj, tags::REFRACTIVE_STATE_SEQUENCE, tag)?;
+        if !value.is_empty() {
+            values.push(format!("{}:{}", label, value));
+        }
+    }
+    Ok(values.join(" "))
+}
+
+/// Illumination, light path filter, acquisition device type and contrast agent, as described
+/// by the device; used to tell apart photography image types
+pub fn acquisition_description(
+    obj: &InMemDicomObject,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut parts = Vec::new();
+    for seq in [
+        tags::ILLUMINATION_TYPE_CODE_SEQUENCE,
+        tags::LIGHT_PATH_FILTER_TYPE_STACK_CODE_SEQUENCE,
+        tags::ACQUISITION_DEVICE_TYPE_CODE_SEQUENCE,
+        tags::CONTRAST_BOLUS_AGENT_SEQUENCE,
+    ] {
+        parts.extend(code_meanings(obj, seq)?);
+    }
+    let contrast_agent = element_str(obj, tags::CONTRAST_BOLUS_AGENT)?;
+    if !contrast_agent.is_empty() {
+        parts.push(contrast_agent);
+    }
+    Ok(parts.join("; "))
+}
+
+/// Fill the Ophthalmic Tomography columns; left empty for any other SOP class.
+/// Only attributes before the pixel data are used.
+pub fn fill_opt_metadata(
+    obj: &InMemDicomObject,
+    data: &mut DicomData,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if data.sop_class_uid != uids::OPHTHALMIC_TOMOGRAPHY_IMAGE_STORAGE {
+        return Ok(());
+    }
+
+    data.number_of_frames = element_str(obj, tags::NUMBER_OF_FRAMES)?;
+    data.rows = element_str(obj, tags::ROWS)?;
+    data.columns = element_str(obj, tags::COLUMNS)?;
+    data.pixel_spacing = match element_str(obj, tags::PIXEL_SPACING)? {
+        spacing if !spacing.is_empty() => spacing,
+        _ => pixel_measure(obj, tags::PIXEL_SPACING)?,
+    };
+    data.slice_thickness = match element_str(obj, tags::SLICE_THICKNESS)? {
+        thickness if !thickness.is_empty() => thickness,
+        _ => pixel_measure(obj, tags::SLICE_THICKNESS)?,
+    };
+
+    // Ophthalmic Tomography Acquisition Parameters
+    data.axial_length = element_str(obj, tags::AXIAL_LENGTH_OF_THE_EYE)?;
+    data.horizontal_field_of_view = element_str(obj, tags::HORIZONTAL_FIELD_OF_VIEW)?;
+    data.emmetropic_magnification = element_str(obj, tags::EMMETROPIC_MAGNIFICATION)?;
+    data.intra_ocular_pressure = element_str(obj, tags::INTRA_OCULAR_PRESSURE)?;
+    data.pupil_dilated = element_str(obj, tags::PUPIL_DILATED)?;
+    data.detector_type = element_str(obj, tags::DETECTOR_TYPE)?;
+    data.illumination_wave_length = element_str(obj, tags::ILLUMINATION_WAVE_LENGTH)?;
+    data.depth_spatial_resolution = element_str(obj, tags::DEPTH_SPATIAL_RESOLUTION)?;
+    data.along_scan_spatial_resolution = element_str(obj, tags::ALONG_SCAN_SPATIAL_RESOLUTION)?;
+    data.across_scan_spatial_resolution = element_str(obj, tags::ACROSS_SCAN_SPATIAL_RESOLUTION)?;
+    data.refractive_state = refractive_state(obj)?;
+
+    data.acquisition_device_type = match item_str(
+        obj,
+        tags::ACQUISITION_DEVICE_TYPE_CODE_SEQUENCE,
+        tags::CODE_MEANING,
+    )? {
+        meaning if !meaning.is_empty() => meaning,
+        _ => item_str(
+            obj,
+            tags::ACQUISITION_DEVICE_TYPE_CODE_SEQUENCE,
+            tags::CODE_VALUE,
+        )?,
+    };
+
+    Ok(())
+}
+
+/// Row of the visual field side table, one per Static Perimetry Measurements object
+#[derive(Debug, Default, Serialize)]
+pub struct VisualFieldData {
+    // Left out of research-safe indexes, as it links back to the source
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sop_instance_uid: Option<String>,
+    laterality: String,
+    test_pattern: String,
+    test_strategy: String,
+    mean_deviation: String,
+    pattern_standard_deviation: String,
+    visual_field_index: String,
+    fixation_checked: String,
+    fixation_losses: String,
+    false_positives_estimate: String,
+    false_negatives_estimate: String,
+    foveal_sensitivity: String,
+    test_duration: String,
+    // One or the other, `file_id` in research-safe indexes
+    #[serde(skip_serializing_if = "String::is_empty")]
+    file_path: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    file_id: String,
+}
+
+/// Row of the axial measurements side table, one per Axial Measurements or IOL Calculation object
+#[derive(Debug, Default, Serialize)]
+pub struct AxialMeasurementData {
+    // Left out of research-safe indexes, as it links back to the source
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sop_instance_uid: Option<String>,
+    sop_class_uid: String,
+    device_type: String,
+    axial_length_right: String,
+    axial_length_left: String,
+    // One or the other, `file_id` in research-safe indexes
+    #[serde(skip_serializing_if = "String::is_empty")]
+    file_path: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    file_id: String,
+}
+
+/// Modality-specific data written to a side table, linked by `file_path` (or `file_id`) and SOP
+/// Instance UID
+#[derive(Debug)]
+pub enum SideData {
+    VisualField(VisualFieldData),
+    AxialMeasurement(AxialMeasurementData),
+}
+
+impl SideData {
+    pub fn set_file_path(&mut self, file_path: &str) {
+        match self {
+            SideData::VisualField(row) => row.file_path = file_path.to_string(),
+            SideData::AxialMeasurement(row) => row.file_path = file_path.to_string(),
+        }
+    }
+
+    /// Link to the main table by keyed file ID only, leaving out `file_path` and the SOP
+    /// Instance UID
+    pub fn set_file_id(&mut self, file_id: &str) {
+        let (sop_instance_uid, file_path, id) = match self {
+            SideData::VisualField(row) => (
+                &mut row.sop_instance_uid,
+                &mut row.file_path,
+                &mut row.file_id,
+            ),
+            SideData::AxialMeasurement(row) => (
+                &mut row.sop_instance_uid,
+                &mut row.file_path,
+                &mut row.file_id,
+            ),
+        };
+        *sop_instance_uid = None;
+        file_path.clear();
+        *id = file_id.to_string();
+    }
+}
+
+pub const VISUAL_FIELDS_TABLE: &str = "visual_fields";
+pub const AXIAL_MEASUREMENTS_TABLE: &str = "axial_measurements";
+pub const SIDE_TABLES: [&str; 2] = [VISUAL_FIELDS_TABLE, AXIAL_MEASUREMENTS_TABLE];
+
+/// Side table CSV written next to the main one, e.g. `results_visual_fields.csv`
+pub fn side_table_path(output_path: &Path, table: &str) -> PathBuf {
+    let stem = output_path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    output_path.with_file_name(format!("{}_{}.csv", stem, table))
+}
+
+/// Move the side table rows out of the crawl results
+pub fn take_side_data(
+    results: &mut [DicomData],
+) -> (Vec<VisualFieldData>, Vec<AxialMeasurementData>) {
+    let mut visual_fields = Vec::new();
+    let mut axial_measurements = Vec::new();
+    for data in results.iter_mut() {
+        match data.side_data.take() {
+            Some(SideData::VisualField(row)) => visual_fields.push(row),
+            Some(SideData::AxialMeasurement(row)) => axial_measurements.push(row),
+            None => {}
+        }
+    }
+    (visual_fields, axial_measurements)
+}
+
+/// Value of `tag` in the first item of `seq`, or at the top level for older exports
+fn item_or_top_str(
+    obj: &InMemDicomObject,
+    seq: Tag,
+    tag: Tag,
+) -> Result<String, Box<dyn std::error::Error>> {
+    match item_str(obj, seq, tag)? {
+        value if !value.is_empty() => Ok(value),
+        _ => element_str(obj, tag),
+    }
+}
+
+/// Code meanings of all items of a code sequence
+fn code_meanings(
+    obj: &InMemDicomObject,
+    seq: Tag,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut meanings = Vec::new();
+    if let Some(items) = obj.element_opt(seq)?.and_then(|elem| elem.items()) {
+        for item in items {
+            let meaning = element_str(item, tags::CODE_MEANING)?;
+            if !meaning.is_empty() {
+                meanings.push(meaning);
+            }
+        }
+    }
+    Ok(meanings)
+}
+
+/// Numeric value of the global index whose concept name contains `name`
+fn global_index(obj: &InMemDicomObject, name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let Some(items) = obj
+        .element_opt(tags::VISUAL_FIELD_GLOBAL_RESULTS_INDEX_SEQUENCE)?
+        .and_then(|elem| elem.items())
+    else {
+        return Ok(String::new());
+    };
+    for item in items {
+        let Some(observations) = item
+            .element_opt(tags::DATA_OBSERVATION_SEQUENCE)?
+            .and_then(|elem| elem.items())
+        else {
+            continue;
+        };
+        for observation in observations {
+            let concept = item_str(
+                observation,
+                tags::CONCEPT_NAME_CODE_SEQUENCE,
+                tags::CODE_MEANING,
+            )?;
+            if concept.to_lowercase().contains(name) {
+                return element_str(observation, tags::NUMERIC_VALUE);
+            }
+        }
+    }
+    Ok(String::new())
+}
+
+fn visual_field_data(
+    obj: &InMemDicomObject,
+) -> Result<VisualFieldData, Box<dyn std::error::Error>> {
+    // Test pattern and strategy are both coded in the Performed Protocol Code Sequence
+    let mut test_pattern = Vec::new();
+    let mut test_strategy = Vec::new();
+    for meaning in code_meanings(obj, tags::PERFORMED_PROTOCOL_CODE_SEQUENCE)? {
+        if meaning.to_lowercase().contains("strategy") {
+            test_strategy.push(meaning);
+        } else {
+            test_pattern.push(meaning);
+        }
+    }
+
+    let mean_deviation = match element_str(obj, tags::GLOBAL_DEVIATION_FROM_NORMAL)? {
+        value if !value.is_empty() => value,
+        _ => global_index(obj, "mean deviation")?,
+    };
+    let pattern_standard_deviation = match element_str(obj, tags::LOCALIZED_DEVIATION_FROM_NORMAL)?
+    {
+        value if !value.is_empty() => value,
+        _ => global_index(obj, "pattern standard deviation")?,
+    };
+
+    Ok(VisualFieldData {
+        laterality: element_str(obj, tags::LATERALITY)?,
+        test_pattern: test_pattern.join("; "),
+        test_strategy: test_strategy.join("; "),
+        mean_deviation,
+        pattern_standard_deviation,
+        visual_field_index: global_index(obj, "visual field index")?,
+        fixation_checked: item_or_top_str(
+            obj,
+            tags::FIXATION_SEQUENCE,
+            tags::FIXATION_CHECKED_QUANTITY,
+        )?,
+        fixation_losses: item_or_top_str(
+            obj,
+            tags::FIXATION_SEQUENCE,
+            tags::PATIENT_NOT_PROPERLY_FIXATED_QUANTITY,
+        )?,
+        false_positives_estimate: item_or_top_str(
+            obj,
+            tags::VISUAL_FIELD_CATCH_TRIAL_SEQUENCE,
+            tags::FALSE_POSITIVES_ESTIMATE,
+        )?,
+        false_negatives_estimate: item_or_top_str(
+            obj,
+            tags::VISUAL_FIELD_CATCH_TRIAL_SEQUENCE,
+            tags::FALSE_NEGATIVES_ESTIMATE,
+        )?,
+        foveal_sensitivity: element_str(obj, tags::FOVEAL_SENSITIVITY)?,
+        test_duration: element_str(obj, tags::VISUAL_FIELD_TEST_DURATION)?,
+        ..Default::default()
+    })
+}
+
+/// First Ophthalmic Axial Length found depth-first under `obj`
+fn find_axial_length(obj: &InMemDicomObject) -> Result<String, Box<dyn std::error::Error>> {
+    let value = element_str(obj, tags::OPHTHALMIC_AXIAL_LENGTH)?;
+    if !value.is_empty() {
+        return Ok(value);
+    }
+    for elem in obj.iter() {
+        for item in elem.items().unwrap_or_default() {
+            let value = find_axial_length(item)?;
+            if !value.is_empty() {
+                return Ok(value);
+            }
+        }
+    }
+    Ok(String::new())
+}
+
+/// Axial length of one eye, preferring the selected (optical, then ultrasound) value
+fn eye_axial_length(
+    obj: &InMemDicomObject,
+    eye_seq: Tag,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let Some(eye) = obj
+        .element_opt(eye_seq)?
+        .and_then(|elem| elem.items()?.first())
+    else {
+        return Ok(String::new());
+    };
+    for selected in [
+        tags::OPTICAL_SELECTED_OPHTHALMIC_AXIAL_LENGTH_SEQUENCE,
+        tags::ULTRASOUND_SELECTED_OPHTHALMIC_AXIAL_LENGTH_SEQUENCE,
+        tags::SELECTED_TOTAL_OPHTHALMIC_AXIAL_LENGTH_SEQUENCE,
+    ] {
+        if let Some(items) = eye.element_opt(selected)?.and_then(|elem| elem.items()) {
+            for item in items {
+                let value = find_axial_length(item)?;
+                if !value.is_empty() {
+                    return Ok(value);
+                }
+            }
+        }
+    }
+    find_axial_length(eye)
+}
+
+/// Side table data for visual field, axial measurements and IOL calculation objects
+pub fn side_data(
+    obj: &InMemDicomObject,
+    data: &DicomData,
+) -> Result<Option<SideData>, Box<dyn std::error::Error>> {
+    let side = match data.sop_class_uid.as_str() {
+        uids::OPHTHALMIC_VISUAL_FIELD_STATIC_PERIMETRY_MEASUREMENTS_STORAGE => {
+            SideData::VisualField(VisualFieldData {
+                sop_instance_uid: Some(data.sop_instance_uid.clone()),
+                file_path: data.file_path.clone(),
+                ..visual_field_data(obj)?
+            })
+        }
+        uids::OPHTHALMIC_AXIAL_MEASUREMENTS_STORAGE => {
+            SideData::AxialMeasurement(AxialMeasurementData {
+                sop_instance_uid: Some(data.sop_instance_uid.clone()),
+                sop_class_uid: data.sop_class_uid.clone(),
+                device_type: element_str(obj, tags::OPHTHALMIC_AXIAL_MEASUREMENTS_DEVICE_TYPE)?,
+                axial_length_right: eye_axial_length(
+                    obj,
+                    tags::OPHTHALMIC_AXIAL_MEASUREMENTS_RIGHT_EYE_SEQUENCE,
+                )?,
+                axial_length_left: eye_axial_length(
+                    obj,
+                    tags::OPHTHALMIC_AXIAL_MEASUREMENTS_LEFT_EYE_SEQUENCE,
+                )?,
+                file_path: data.file_path.clone(),
+                file_id: String::new(),
+            })
+        }
+        uids::INTRAOCULAR_LENS_CALCULATIONS_STORAGE => {
+            SideData::AxialMeasurement(AxialMeasurementData {
+                sop_instance_uid: Some(data.sop_instance_uid.clone()),
+                sop_class_uid: data.sop_class_uid.clone(),
+                device_type: String::new(),
+                axial_length_right: eye_axial_length(
+                    obj,
+                    tags::INTRAOCULAR_LENS_CALCULATIONS_RIGHT_EYE_SEQUENCE,
+                )?,
+                axial_length_left: eye_axial_length(
+                    obj,
+                    tags::INTRAOCULAR_LENS_CALCULATIONS_LEFT_EYE_SEQUENCE,
+                )?,
+                file_path: data.file_path.clone(),
+                file_id: String::new(),
+            })
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(side))
+}
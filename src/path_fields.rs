@@ -0,0 +1,112 @@
+use regex::Regex;
+
+use crate::helpers::normalise_separators;
+
+/// Pattern extracting named fields from file paths, below a root or anywhere
+#[derive(Debug)]
+pub struct PathPattern {
+    /// Root the pattern applies to, with `/` separators; `None` for any path
+    root: Option<String>,
+    regex: Regex,
+}
+
+impl PathPattern {
+    /// Parse `[ROOT=>]PATTERN`, split as `--path-map` is since a regex may hold `=`. A pattern
+    /// with `{field}` placeholders is a template matched from the start of the path below the
+    /// root (from any folder without one), a placeholder standing for one folder or file name
+    /// (or part of it) and `*` for anything within one; any other pattern is a regex whose
+    /// named groups are the fields.
+    pub fn parse(value: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let (root, pattern) = match value.split_once("=>") {
+            Some((root, pattern)) if !root.is_empty() => {
+                let root = std::fs::canonicalize(root)
+                    .map(|root| root.to_string_lossy().to_string())
+                    .unwrap_or_else(|_| root.to_string());
+                (
+                    Some(
+                        normalise_separators(&root)
+                            .trim_end_matches('/')
+                            .to_string(),
+                    ),
+                    pattern,
+                )
+            }
+            _ => (None, value),
+        };
+        let regex = if is_template(pattern) {
+            template_regex(pattern, root.is_some())?
+        } else {
+            Regex::new(pattern)?
+        };
+        Ok(PathPattern { root, regex })
+    }
+
+    /// Named fields of a file path, `None` if it is not below the root or does not match
+    fn fields(&self, file_path: &str) -> Option<Vec<(String, String)>> {
+        let path = normalise_separators(file_path);
+        let path = match &self.root {
+            Some(root) => path.strip_prefix(root.as_str())?.strip_prefix('/')?,
+            None => &path,
+        };
+        let captures = self.regex.captures(path)?;
+        Some(
+            self.regex
+                .capture_names()
+                .flatten()
+                .filter_map(|name| {
+                    let value = captures.name(name)?.as_str();
+                    Some((name.to_string(), value.to_string()))
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Fields of the first pattern matching a file path, empty if none does
+pub fn path_fields(patterns: &[PathPattern], file_path: &str) -> Vec<(String, String)> {
+    patterns
+        .iter()
+        .find_map(|pattern| pattern.fields(file_path))
+        .unwrap_or_default()
+}
+
+/// Whether a pattern is a template: it has `{field}` placeholders, whereas the braces of a
+/// regex are quantifiers such as `{8}` or `{2,4}`, and no named group
+fn is_template(pattern: &str) -> bool {
+    let placeholder = pattern.split('{').skip(1).any(|rest| {
+        rest.split_once('}').is_some_and(|(name, _)| {
+            let name = name.trim();
+            name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+    });
+    placeholder && !pattern.contains("(?P<") && !pattern.contains("(?<")
+}
+
+/// Regex of a template such as `{patient_id}/{scan_date}/*`, anchored at the start of the
+/// path below the root, or of any folder
+fn template_regex(template: &str, below_root: bool) -> Result<Regex, regex::Error> {
+    let mut regex = String::from(if below_root { "^" } else { "(?:^|/)" });
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        regex.push_str(&literal(&rest[..start]));
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .unwrap_or(rest.len());
+        let name = rest[start + 1..end].trim();
+        regex.push_str(&format!("(?P<{}>[^/]+?)", name));
+        rest = rest.get(end + 1..).unwrap_or_default();
+    }
+    regex.push_str(&literal(rest));
+    // The template may stop at any folder
+    regex.push_str("(?:/|$)");
+    Regex::new(&regex)
+}
+
+fn literal(text: &str) -> String {
+    text.split('*')
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join("[^/]*")
+}
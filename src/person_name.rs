@@ -0,0 +1,101 @@
+use dicom_dictionary_std::tags;
+use dicom_encoding::text::{SpecificCharacterSet, TextCodec};
+use dicom_object::InMemDicomObject;
+
+use crate::element_str;
+
+/// Components of a person name, as in a DICOM PN value
+#[derive(Debug, Default)]
+pub struct PersonName {
+    pub family: String,
+    pub given: String,
+    pub middle: String,
+    pub prefix: String,
+    pub suffix: String,
+}
+
+impl PersonName {
+    /// Parse a PN value: first value, first non-empty component group (alphabetic, then
+    /// ideographic, then phonetic), components `family^given^middle^prefix^suffix`
+    pub fn parse(pn: &str) -> Self {
+        let value = pn.split('\\').next().unwrap_or_default();
+        let group = value
+            .split('=')
+            .find(|group| group.chars().any(|c| c != '^' && !c.is_whitespace()))
+            .unwrap_or_default();
+        let mut components = group.split('^').map(|c| c.trim().to_string());
+        PersonName {
+            family: components.next().unwrap_or_default(),
+            given: components.next().unwrap_or_default(),
+            middle: components.next().unwrap_or_default(),
+            prefix: components.next().unwrap_or_default(),
+            suffix: components.next().unwrap_or_default(),
+        }
+    }
+
+    /// Name given as first and last name, extra first names taken as middle names
+    pub fn from_first_last(first_name: &str, last_name: &str) -> Self {
+        let mut first_names = first_name.split_whitespace();
+        PersonName {
+            family: last_name.trim().to_string(),
+            given: first_names.next().unwrap_or_default().to_string(),
+            middle: first_names.collect::<Vec<_>>().join(" "),
+            ..Default::default()
+        }
+    }
+
+    /// The name written the DICOM way, `family^given^middle^prefix^suffix`, without trailing `^`
+    pub fn to_pn(&self) -> String {
+        [
+            &self.family,
+            &self.given,
+            &self.middle,
+            &self.prefix,
+            &self.suffix,
+        ]
+        .map(|c| c.as_str())
+        .join("^")
+        .trim_end_matches('^')
+        .to_string()
+    }
+}
+
+/// Text of a PN element decoded as declared by SpecificCharacterSet.
+///
+/// The parser decodes with the first declared character set only, falling back on ISO-IR 6
+/// (read as Latin-1) when it is empty, as with ISO 2022 code extensions (`\ISO 2022 IR 87`),
+/// or unsupported. In that case the original bytes are recovered and decoded again with the
+/// first supported extension; when no character set is declared at all, UTF-8 is tried.
+pub fn decoded_person_name(
+    obj: &InMemDicomObject,
+    tag: dicom_object::Tag,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let text = element_str(obj, tag)?;
+    // Only text decoded as Latin-1 can be turned back into its bytes; ISO 2022 escape
+    // sequences are ASCII
+    if (text.is_ascii() && !text.contains('\u{1b}')) || text.chars().any(|c| c as u32 > 0xFF) {
+        return Ok(text);
+    }
+    let declared = element_str(obj, tags::SPECIFIC_CHARACTER_SET)?;
+    let charsets: Vec<&str> = declared.split('\\').map(str::trim).collect();
+    let first = charsets
+        .first()
+        .and_then(|code| SpecificCharacterSet::from_code(code))
+        .unwrap_or_default();
+    if first != SpecificCharacterSet::ISO_IR_6 {
+        return Ok(text);
+    }
+
+    let bytes: Vec<u8> = text.chars().map(|c| c as u8).collect();
+    let extension = charsets
+        .iter()
+        .skip(1)
+        .filter_map(|code| SpecificCharacterSet::from_code(code))
+        .find(|charset| *charset != SpecificCharacterSet::ISO_IR_6);
+    let decoded = match extension {
+        Some(charset) => charset.decode(&bytes).ok(),
+        None if declared.is_empty() => String::from_utf8(bytes).ok(),
+        None => None,
+    };
+    Ok(decoded.unwrap_or(text))
+}
@@ -0,0 +1,8 @@
+/// Dates standing in for unknown ones (mostly DOBs) in some exports, `YYYY-mm-dd`
+pub const PLACEHOLDER_DATES: &[&str] = &[
+    "1900-01-01",
+    "1899-12-30",
+    "1899-12-31",
+    "1800-01-01",
+    "0001-01-01",
+];
@@ -0,0 +1,252 @@
+use chrono::Datelike;
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::dates::{age_in_years, parse_date};
+use crate::helpers::to_hex;
+use crate::{save_results_to_csv, DicomData};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Minimum length of the HMAC secret, in bytes
+const MIN_SECRET_LEN: usize = 16;
+
+/// How the date of birth is reduced in the research-safe index
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DobMode {
+    /// Keep only the year of birth
+    Year,
+    /// Replace the date of birth by the age at scan, in whole years
+    Age,
+}
+
+/// Research-safe row: keyed pseudonym instead of patient ID, no name, reduced DOB
+#[derive(Debug, Serialize)]
+pub struct ResearchData {
+    patient_id: String,
+    laterality: String,
+    laterality_source: String,
+    sex: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    birth_year: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    age_at_scan: Option<String>,
+    scan_date: String,
+    date_flags: String,
+    source_format: String,
+    path_flags: String,
+    modality: String,
+    image_type: String,
+    manufacturer: String,
+    manufacturer_normalised: String,
+    manufacturer_model_name: String,
+    software_versions: String,
+    series_description: String,
+    modified: String,
+    file_size: u64,
+    /// Keyed ID of `file_path`, which may name the patient
+    file_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MappingRow {
+    pseudonym: String,
+    patient_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FileMappingRow {
+    file_id: String,
+    file_path: String,
+}
+
+/// File ID to `file_path` table written next to the mapping table, e.g. `pseudonyms_files.csv`
+pub fn file_mapping_path(mapping_path: &Path) -> PathBuf {
+    let stem = mapping_path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    mapping_path.with_file_name(format!("{}_files.csv", stem))
+}
+
+pub struct Pseudonymiser {
+    key: Vec<u8>,
+    dob_mode: DobMode,
+    mapping_path: PathBuf,
+    file_mapping_path: PathBuf,
+    known: HashSet<String>,
+}
+
+impl Pseudonymiser {
+    /// Load the secret and any existing mapping table
+    pub fn new(
+        secret_file: &Path,
+        mapping_path: &Path,
+        dob_mode: DobMode,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let key = fs::read_to_string(secret_file)
+            .map_err(|e| format!("cannot read secret file {:?}: {}", secret_file, e))?
+            .trim()
+            .as_bytes()
+            .to_vec();
+        if key.len() < MIN_SECRET_LEN {
+            return Err(format!(
+                "secret in {:?} is too short, need at least {} characters",
+                secret_file, MIN_SECRET_LEN
+            )
+            .into());
+        }
+
+        let mut known = HashSet::new();
+        if mapping_path.exists() {
+            let file = File::open(mapping_path)?;
+            let mut rdr = csv::Reader::from_reader(BufReader::new(file));
+            for result in rdr.deserialize() {
+                let row: MappingRow = result?;
+                known.insert(row.pseudonym);
+            }
+        }
+
+        Ok(Pseudonymiser {
+            key,
+            dob_mode,
+            mapping_path: mapping_path.to_path_buf(),
+            file_mapping_path: file_mapping_path(mapping_path),
+            known,
+        })
+    }
+
+    /// Keyed HMAC-SHA256 of the parts, truncated to 128 bits, hex encoded
+    fn keyed_hash(&self, parts: &[&[u8]]) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        for part in parts {
+            mac.update(part);
+        }
+        to_hex(&mac.finalize().into_bytes()[..16])
+    }
+
+    /// Keyed HMAC-SHA256 of the patient ID, truncated to 128 bits, hex encoded
+    pub fn pseudonym(&self, patient_id: &str) -> String {
+        if patient_id.is_empty() {
+            return String::new();
+        }
+        self.keyed_hash(&[patient_id.as_bytes()])
+    }
+
+    /// Keyed ID of a file path, distinct from the pseudonyms of patient IDs
+    pub fn file_id(&self, file_path: &str) -> String {
+        if file_path.is_empty() {
+            return String::new();
+        }
+        self.keyed_hash(&[b"file_path:", file_path.as_bytes()])
+    }
+
+    /// Side table rows linked by file ID rather than `file_path`
+    pub fn apply_side_data(&self, results: &mut [DicomData]) {
+        for data in results.iter_mut() {
+            let file_id = self.file_id(&data.file_path);
+            if let Some(side_data) = &mut data.side_data {
+                side_data.set_file_id(&file_id);
+            }
+        }
+    }
+
+    /// Paths of the files whose IDs are in a research-safe index, from the file mapping table
+    pub fn file_paths(
+        &self,
+        file_ids: &HashSet<String>,
+    ) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+        let mut file_paths = HashSet::new();
+        if self.file_mapping_path.exists() {
+            let file = File::open(&self.file_mapping_path)?;
+            let mut rdr = csv::Reader::from_reader(BufReader::new(file));
+            for result in rdr.deserialize() {
+                let row: FileMappingRow = result?;
+                if file_ids.contains(&row.file_id) {
+                    file_paths.insert(row.file_path);
+                }
+            }
+        }
+        Ok(file_paths)
+    }
+
+    /// Convert rows into research-safe rows, appending new pseudonyms to the mapping table
+    pub fn apply(
+        &mut self,
+        results: Vec<DicomData>,
+    ) -> Result<Vec<ResearchData>, Box<dyn std::error::Error>> {
+        let mut new_rows = Vec::new();
+        let mut file_rows = Vec::new();
+        let rows = results
+            .into_iter()
+            .map(|data| {
+                let pseudonym = self.pseudonym(&data.patient_id);
+                if !pseudonym.is_empty() && self.known.insert(pseudonym.clone()) {
+                    new_rows.push(MappingRow {
+                        pseudonym: pseudonym.clone(),
+                        patient_id: data.patient_id.clone(),
+                    });
+                }
+                let file_id = self.file_id(&data.file_path);
+                if !file_id.is_empty() {
+                    file_rows.push(FileMappingRow {
+                        file_id: file_id.clone(),
+                        file_path: data.file_path.clone(),
+                    });
+                }
+                let dob = parse_date(&data.dob);
+                let (birth_year, age_at_scan) = match self.dob_mode {
+                    DobMode::Year => (
+                        Some(dob.map(|d| d.year().to_string()).unwrap_or_default()),
+                        None,
+                    ),
+                    // Whole years: with the exact scan date, the age in decimals would give
+                    // the date of birth within days
+                    DobMode::Age => {
+                        let age = dob.zip(parse_date(&data.scan_date)).map(|(dob, scan)| {
+                            (age_in_years(dob, scan).floor() as i64).to_string()
+                        });
+                        (None, Some(age.unwrap_or_default()))
+                    }
+                };
+                ResearchData {
+                    patient_id: pseudonym,
+                    laterality: data.laterality,
+                    laterality_source: data.laterality_source,
+                    sex: data.sex,
+                    birth_year,
+                    age_at_scan,
+                    scan_date: data.scan_date,
+                    date_flags: data.date_flags,
+                    source_format: data.source_format,
+                    path_flags: data.path_flags,
+                    modality: data.modality,
+                    image_type: data.image_type,
+                    manufacturer: data.manufacturer,
+                    manufacturer_normalised: data.manufacturer_normalised,
+                    manufacturer_model_name: data.manufacturer_model_name,
+                    software_versions: data.software_versions,
+                    series_description: data.series_description,
+                    modified: data.modified,
+                    file_size: data.file_size,
+                    file_id,
+                }
+            })
+            .collect();
+
+        if !new_rows.is_empty() {
+            save_results_to_csv(&new_rows, &self.mapping_path)?;
+        }
+        if !file_rows.is_empty() {
+            save_results_to_csv(&file_rows, &self.file_mapping_path)?;
+        }
+
+        Ok(rows)
+    }
+}
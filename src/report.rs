@@ -0,0 +1,636 @@
+use chrono::{Local, NaiveDateTime, SecondsFormat, Utc};
+use clap::{Parser, ValueEnum};
+use duckdb::Connection;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+mod consistency;
+mod placeholder_dates;
+mod sql;
+use consistency::PatientConflict;
+use placeholder_dates::PLACEHOLDER_DATES;
+use sql::{quote_ident, quote_literal, strptime_expr, DATETIME_FORMATS, DATE_FORMATS};
+
+/// Command line arguments
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Report on the completeness and consistency of an open-sight index", long_about = None)]
+struct Opt {
+    /// CSV file created by open-sight, or DuckDB database created by ingest
+    #[arg(name = "INPUT")]
+    input: PathBuf,
+
+    /// Table to report on, when the input is a DuckDB database
+    #[arg(short = 't', long = "table", default_value = "open_sight")]
+    table: String,
+
+    /// Output file name, without extension: `<OUTPUT>.json`, `<OUTPUT>.md` or `.html`, and the
+    /// patient conflicts table `<OUTPUT>_conflicts.csv` are written
+    #[arg(short = 'o', long = "output", default_value = "open_sight_report")]
+    output: PathBuf,
+
+    /// Format of the readable summary
+    #[arg(short = 'f', long = "format", value_enum, default_value_t = SummaryFormat::Markdown)]
+    format: SummaryFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SummaryFormat {
+    Markdown,
+    Html,
+}
+
+/// View every check runs on, all columns as VARCHAR
+const ROWS: &str = "report_rows";
+
+#[derive(Debug, Serialize)]
+struct Report {
+    source: String,
+    generated: String,
+    rows: usize,
+    empty_patient_ids: usize,
+    completeness: Vec<GroupCompleteness>,
+    patient_conflicts: Vec<PatientConflict>,
+    duplicate_sop_instance_uids: Vec<DuplicateUid>,
+    date_issues: Vec<DateIssue>,
+}
+
+/// Share of non-empty values of each column, for one manufacturer and modality
+#[derive(Debug, Serialize)]
+struct GroupCompleteness {
+    manufacturer: String,
+    modality: String,
+    rows: usize,
+    columns: Vec<ColumnCompleteness>,
+}
+
+#[derive(Debug, Serialize)]
+struct ColumnCompleteness {
+    column: String,
+    filled: usize,
+    percent: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicateUid {
+    sop_instance_uid: String,
+    rows: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct DateIssue {
+    issue: String,
+    rows: usize,
+}
+
+/// Table of the readable summary
+struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+fn is_empty_expr(column: &str) -> String {
+    format!("NULLIF(trim({}), '') IS NULL", quote_ident(column))
+}
+
+/// Expose the crawl output as the `report_rows` view, reading a CSV as text or attaching
+/// a database read-only
+fn open_input(
+    conn: &Connection,
+    input: &Path,
+    table: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let input_str = input.to_str().ok_or("Invalid input path")?;
+    let is_csv = input
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+    if is_csv {
+        conn.execute_batch(&format!(
+            "CREATE VIEW {} AS SELECT * FROM read_csv({}, header = true, all_varchar = true, auto_detect = true);",
+            ROWS,
+            quote_literal(input_str)
+        ))?;
+        return Ok(());
+    }
+
+    conn.execute_batch(&format!(
+        "ATTACH {} AS src (READ_ONLY);",
+        quote_literal(input_str)
+    ))?;
+    let mut stmt = conn.prepare(
+        "SELECT column_name FROM information_schema.columns WHERE table_catalog = 'src' AND table_name = ? ORDER BY ordinal_position",
+    )?;
+    let columns = stmt
+        .query_map([table], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    if columns.is_empty() {
+        return Err(format!("no table {} in {}", table, input.display()).into());
+    }
+    // Same text as in the CSV, so that the checks do not depend on the input
+    let casts: Vec<String> = columns
+        .iter()
+        .map(|c| format!("CAST({col} AS VARCHAR) AS {col}", col = quote_ident(c)))
+        .collect();
+    conn.execute_batch(&format!(
+        "CREATE VIEW {} AS SELECT {} FROM src.{};",
+        ROWS,
+        casts.join(", "),
+        quote_ident(table)
+    ))?;
+    Ok(())
+}
+
+fn view_columns(conn: &Connection) -> Result<Vec<String>, duckdb::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT column_name FROM information_schema.columns WHERE table_name = ? ORDER BY ordinal_position",
+    )?;
+    let columns = stmt
+        .query_map([ROWS], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(columns)
+}
+
+fn completeness(
+    conn: &Connection,
+    columns: &[String],
+) -> Result<Vec<GroupCompleteness>, duckdb::Error> {
+    // The normalised name groups together the spellings of one manufacturer
+    let manufacturer = ["manufacturer_normalised", "manufacturer"]
+        .into_iter()
+        .find(|c| columns.iter().any(|col| col == c))
+        .map_or("''".to_string(), quote_ident);
+    let modality = if columns.iter().any(|c| c == "modality") {
+        quote_ident("modality")
+    } else {
+        "''".to_string()
+    };
+    let counts: Vec<String> = columns
+        .iter()
+        .map(|c| format!("count(NULLIF(trim({}), ''))", quote_ident(c)))
+        .collect();
+    let query = format!(
+        "SELECT coalesce({m}, ''), coalesce({mo}, ''), count(*), {counts} FROM {rows} GROUP BY 1, 2 ORDER BY 3 DESC, 1, 2",
+        m = manufacturer,
+        mo = modality,
+        counts = counts.join(", "),
+        rows = ROWS
+    );
+    let mut stmt = conn.prepare(&query)?;
+    let groups = stmt
+        .query_map([], |row| {
+            let rows: usize = row.get(2)?;
+            let mut column_completeness = Vec::new();
+            for (i, column) in columns.iter().enumerate() {
+                let filled: usize = row.get(3 + i)?;
+                column_completeness.push(ColumnCompleteness {
+                    column: column.clone(),
+                    filled,
+                    percent: (1000.0 * filled as f64 / rows as f64).round() / 10.0,
+                });
+            }
+            Ok(GroupCompleteness {
+                manufacturer: row.get(0)?,
+                modality: row.get(1)?,
+                rows,
+                columns: column_completeness,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(groups)
+}
+
+fn duplicate_uids(conn: &Connection) -> Result<Vec<DuplicateUid>, duckdb::Error> {
+    let query = format!(
+        "SELECT sop_instance_uid, count(*) FROM {rows}
+         WHERE NOT ({empty})
+         GROUP BY sop_instance_uid
+         HAVING count(*) > 1
+         ORDER BY 2 DESC, 1",
+        rows = ROWS,
+        empty = is_empty_expr("sop_instance_uid"),
+    );
+    let mut stmt = conn.prepare(&query)?;
+    let duplicates = stmt
+        .query_map([], |row| {
+            Ok(DuplicateUid {
+                sop_instance_uid: row.get(0)?,
+                rows: row.get(1)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(duplicates)
+}
+
+/// Counts of unparsable and impossible dates, for the date columns present
+fn date_issues(
+    conn: &Connection,
+    columns: &[String],
+    now: NaiveDateTime,
+) -> Result<Vec<DateIssue>, duckdb::Error> {
+    // Literals rather than current_date/now(), which need the ICU extension
+    let today = format!("DATE {}", quote_literal(&now.date().to_string()));
+    let now = format!(
+        "TIMESTAMP {}",
+        quote_literal(&now.format("%Y-%m-%d %H:%M:%S").to_string())
+    );
+    let has = |column: &str| columns.iter().any(|c| c == column);
+    let date = |column: &str| {
+        format!(
+            "CAST({} AS DATE)",
+            strptime_expr(&quote_ident(column), DATE_FORMATS)
+        )
+    };
+    let placeholders: Vec<String> = PLACEHOLDER_DATES
+        .iter()
+        .map(|d| format!("DATE {}", quote_literal(d)))
+        .collect();
+    let placeholders = placeholders.join(", ");
+
+    let mut checks: Vec<(&str, String)> = Vec::new();
+    if has("dob") {
+        checks.push((
+            "dob_unparsable",
+            format!("NOT ({}) AND {} IS NULL", is_empty_expr("dob"), date("dob")),
+        ));
+        checks.push((
+            "placeholder_dob",
+            format!("{} IN ({})", date("dob"), placeholders),
+        ));
+        checks.push(("dob_in_future", format!("{} > {}", date("dob"), today)));
+    }
+    if has("scan_date") {
+        checks.push((
+            "scan_date_unparsable",
+            format!(
+                "NOT ({}) AND {} IS NULL",
+                is_empty_expr("scan_date"),
+                date("scan_date")
+            ),
+        ));
+        checks.push((
+            "placeholder_scan_date",
+            format!("{} IN ({})", date("scan_date"), placeholders),
+        ));
+        checks.push((
+            "scan_in_future",
+            format!("{} > {}", date("scan_date"), today),
+        ));
+    }
+    if has("dob") && has("scan_date") {
+        checks.push((
+            "dob_after_scan",
+            format!("{} > {}", date("dob"), date("scan_date")),
+        ));
+        checks.push((
+            "age_over_120",
+            format!(
+                "date_diff('day', {}, {}) / 365.2425 > 120",
+                date("dob"),
+                date("scan_date")
+            ),
+        ));
+    }
+    if has("modified") {
+        let modified = strptime_expr(&quote_ident("modified"), DATETIME_FORMATS);
+        checks.push((
+            "modified_unparsable",
+            format!(
+                "NOT ({}) AND {} IS NULL",
+                is_empty_expr("modified"),
+                modified
+            ),
+        ));
+        checks.push(("modified_in_future", format!("{} > {}", modified, now)));
+    }
+    if checks.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let counts: Vec<String> = checks
+        .iter()
+        .map(|(_, condition)| format!("count(*) FILTER (WHERE {})", condition))
+        .collect();
+    let query = format!("SELECT {} FROM {}", counts.join(", "), ROWS);
+    conn.query_row(&query, [], |row| {
+        checks
+            .iter()
+            .enumerate()
+            .map(|(i, (issue, _))| {
+                Ok(DateIssue {
+                    issue: issue.to_string(),
+                    rows: row.get(i)?,
+                })
+            })
+            .collect()
+    })
+}
+
+fn build_report(conn: &Connection, source: &Path) -> Result<Report, Box<dyn std::error::Error>> {
+    let columns = view_columns(conn)?;
+    let has = |column: &str| columns.iter().any(|c| c == column);
+
+    let rows: usize = conn.query_row(&format!("SELECT count(*) FROM {}", ROWS), [], |row| {
+        row.get(0)
+    })?;
+    let empty_patient_ids: usize = if has("patient_id") {
+        conn.query_row(
+            &format!(
+                "SELECT count(*) FROM {} WHERE {}",
+                ROWS,
+                is_empty_expr("patient_id")
+            ),
+            [],
+            |row| row.get(0),
+        )?
+    } else {
+        rows
+    };
+
+    Ok(Report {
+        source: source.display().to_string(),
+        generated: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        rows,
+        empty_patient_ids,
+        completeness: completeness(conn, &columns)?,
+        patient_conflicts: if has("patient_id") {
+            consistency::patient_conflicts(conn, ROWS, &columns)?
+        } else {
+            Vec::new()
+        },
+        duplicate_sop_instance_uids: if has("sop_instance_uid") {
+            duplicate_uids(conn)?
+        } else {
+            Vec::new()
+        },
+        // Modification times are UTC or local depending on the date format, take the later
+        date_issues: date_issues(
+            conn,
+            &columns,
+            Utc::now().naive_utc().max(Local::now().naive_local()),
+        )?,
+    })
+}
+
+/// Tables of the readable summary, with a heading and a short note each
+fn summary_tables(report: &Report) -> Vec<(String, String, Table)> {
+    let mut sections = Vec::new();
+
+    // One column per manufacturer and modality, one row per crawl column
+    let mut headers = vec!["column".to_string()];
+    headers.extend(report.completeness.iter().map(|g| {
+        format!(
+            "{} / {} ({})",
+            if g.manufacturer.is_empty() {
+                "?"
+            } else {
+                &g.manufacturer
+            },
+            if g.modality.is_empty() {
+                "?"
+            } else {
+                &g.modality
+            },
+            g.rows
+        )
+    }));
+    let column_names: Vec<&String> = report
+        .completeness
+        .first()
+        .map(|g| g.columns.iter().map(|c| &c.column).collect())
+        .unwrap_or_default();
+    let rows = column_names
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            let mut row = vec![column.to_string()];
+            row.extend(
+                report
+                    .completeness
+                    .iter()
+                    .map(|g| format!("{:.1}", g.columns[i].percent)),
+            );
+            row
+        })
+        .collect();
+    sections.push((
+        "Completeness".to_string(),
+        "Percentage of non-empty values, per manufacturer / modality (rows).".to_string(),
+        Table { headers, rows },
+    ));
+
+    sections.push((
+        "Conflicting sex, DOB or name per patient".to_string(),
+        format!(
+            "{} patients whose files disagree, after normalising formats; each value with its source.",
+            consistency::conflicting_patients(&report.patient_conflicts)
+        ),
+        Table {
+            headers: [
+                "patient_id",
+                "field",
+                "value",
+                "source",
+                "rows",
+                "example_file_path",
+            ]
+            .map(String::from)
+            .to_vec(),
+            rows: report
+                .patient_conflicts
+                .iter()
+                .map(|c| {
+                    vec![
+                        c.patient_id.clone(),
+                        c.field.clone(),
+                        c.value.clone(),
+                        c.source.clone(),
+                        c.rows.to_string(),
+                        c.example_file_path.clone(),
+                    ]
+                })
+                .collect(),
+        },
+    ));
+
+    sections.push((
+        "Duplicate SOP Instance UIDs".to_string(),
+        format!(
+            "{} UIDs found in more than one row.",
+            report.duplicate_sop_instance_uids.len()
+        ),
+        Table {
+            headers: ["sop_instance_uid", "rows"].map(String::from).to_vec(),
+            rows: report
+                .duplicate_sop_instance_uids
+                .iter()
+                .map(|d| vec![d.sop_instance_uid.clone(), d.rows.to_string()])
+                .collect(),
+        },
+    ));
+
+    sections.push((
+        "Out-of-range dates".to_string(),
+        "Rows with unparsable or impossible dates.".to_string(),
+        Table {
+            headers: ["issue", "rows"].map(String::from).to_vec(),
+            rows: report
+                .date_issues
+                .iter()
+                .map(|d| vec![d.issue.clone(), d.rows.to_string()])
+                .collect(),
+        },
+    ));
+
+    sections
+}
+
+fn render_markdown(report: &Report) -> String {
+    let mut out = String::from("# open-sight data quality report\n\n");
+    out.push_str(&format!(
+        "- Source: `{}`\n- Generated: {}\n- Rows: {}\n- Empty patient IDs: {}\n",
+        report.source, report.generated, report.rows, report.empty_patient_ids
+    ));
+    let cell = |value: &str| value.replace('|', "\\|");
+    for (heading, note, table) in summary_tables(report) {
+        out.push_str(&format!("\n## {}\n\n{}\n", heading, note));
+        if table.rows.is_empty() {
+            continue;
+        }
+        let headers: Vec<String> = table.headers.iter().map(|h| cell(h)).collect();
+        out.push_str(&format!("\n| {} |\n", headers.join(" | ")));
+        out.push_str(&format!("|{}\n", " --- |".repeat(headers.len())));
+        for row in &table.rows {
+            let cells: Vec<String> = row.iter().map(|c| cell(c)).collect();
+            out.push_str(&format!("| {} |\n", cells.join(" | ")));
+        }
+    }
+    out
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn render_html(report: &Report) -> String {
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>open-sight data quality report</title>\n\
+         <style>body{font-family:sans-serif}table{border-collapse:collapse}th,td{border:1px solid #ccc;padding:2px 6px}td{text-align:right}td:first-child{text-align:left}</style>\n\
+         </head>\n<body>\n<h1>open-sight data quality report</h1>\n",
+    );
+    out.push_str(&format!(
+        "<ul>\n<li>Source: <code>{}</code></li>\n<li>Generated: {}</li>\n<li>Rows: {}</li>\n<li>Empty patient IDs: {}</li>\n</ul>\n",
+        escape_html(&report.source),
+        report.generated,
+        report.rows,
+        report.empty_patient_ids
+    ));
+    for (heading, note, table) in summary_tables(report) {
+        out.push_str(&format!(
+            "<h2>{}</h2>\n<p>{}</p>\n",
+            escape_html(&heading),
+            escape_html(&note)
+        ));
+        if table.rows.is_empty() {
+            continue;
+        }
+        out.push_str("<table>\n<tr>");
+        for header in &table.headers {
+            out.push_str(&format!("<th>{}</th>", escape_html(header)));
+        }
+        out.push_str("</tr>\n");
+        for row in &table.rows {
+            out.push_str("<tr>");
+            for cell in row {
+                out.push_str(&format!("<td>{}</td>", escape_html(cell)));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn conflicts_path(output: &Path) -> PathBuf {
+    let stem = output
+        .file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    output.with_file_name(format!("{}_conflicts.csv", stem))
+}
+
+/// Conflicts table for data managers, header only if there are none
+fn write_conflicts(
+    conflicts: &[PatientConflict],
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wtr = csv::Writer::from_path(path)?;
+    if conflicts.is_empty() {
+        wtr.write_record([
+            "patient_id",
+            "field",
+            "value",
+            "source",
+            "rows",
+            "example_file_path",
+        ])?;
+    }
+    for conflict in conflicts {
+        wtr.serialize(conflict)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn main() {
+    let args = Opt::parse();
+
+    let conn = Connection::open_in_memory().unwrap_or_else(|err| {
+        eprintln!("Error opening DuckDB: {}", err);
+        process::exit(1);
+    });
+    if let Err(err) = open_input(&conn, &args.input, &args.table) {
+        eprintln!("Error reading {}: {}", args.input.display(), err);
+        process::exit(1);
+    }
+    let report = build_report(&conn, &args.input).unwrap_or_else(|err| {
+        eprintln!("Error building report: {}", err);
+        process::exit(1);
+    });
+
+    let json_path = args.output.with_extension("json");
+    let (summary_path, summary) = match args.format {
+        SummaryFormat::Markdown => (args.output.with_extension("md"), render_markdown(&report)),
+        SummaryFormat::Html => (args.output.with_extension("html"), render_html(&report)),
+    };
+    let json = serde_json::to_string_pretty(&report).expect("report serialises to JSON");
+    for (path, contents) in [(&json_path, json), (&summary_path, summary)] {
+        if let Err(err) = fs::write(path, contents) {
+            eprintln!("Error writing {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    }
+    let conflicts_path = conflicts_path(&args.output);
+    if let Err(err) = write_conflicts(&report.patient_conflicts, &conflicts_path) {
+        eprintln!("Error writing {}: {}", conflicts_path.display(), err);
+        process::exit(1);
+    }
+
+    println!(
+        ">> {} rows, {} empty patient IDs, {} patients with conflicting sex/DOB/name, {} duplicate SOP Instance UIDs",
+        report.rows,
+        report.empty_patient_ids,
+        consistency::conflicting_patients(&report.patient_conflicts),
+        report.duplicate_sop_instance_uids.len()
+    );
+    println!(
+        ">> Report written to {}, {} and {}",
+        json_path.display(),
+        summary_path.display(),
+        conflicts_path.display()
+    );
+}
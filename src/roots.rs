@@ -0,0 +1,19 @@
+use std::path::PathBuf;
+
+/// Folder files are indexed under, with the label of its site
+#[derive(Debug, Clone)]
+pub struct Root {
+    pub label: String,
+    pub path: PathBuf,
+}
+
+/// `LABEL=PATH` of `--root`
+pub fn parse_root(value: &str) -> Result<Root, String> {
+    match value.split_once('=') {
+        Some((label, path)) if !label.trim().is_empty() && !path.is_empty() => Ok(Root {
+            label: label.trim().to_string(),
+            path: PathBuf::from(path),
+        }),
+        _ => Err(format!("expected LABEL=PATH, got {}", value)),
+    }
+}
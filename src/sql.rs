@@ -0,0 +1,29 @@
+/// Accepted formats, ISO 8601 first, then the legacy `dd-mm-YYYY` ones
+pub const DATE_FORMATS: &[&str] = &["%Y-%m-%d", "%d-%m-%Y"];
+pub const DATETIME_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%SZ",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S.%f",
+    // As DuckDB writes TIMESTAMP values
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M:%S.%f",
+    "%d-%m-%Y %H:%M:%S",
+    "%d-%m-%Y %H:%M:%S.%f",
+];
+
+pub fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+pub fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// First successful `try_strptime` of `col` over the given formats
+pub fn strptime_expr(col: &str, formats: &[&str]) -> String {
+    let attempts: Vec<String> = formats
+        .iter()
+        .map(|f| format!("try_strptime(NULLIF({}, ''), {})", col, quote_literal(f)))
+        .collect();
+    format!("coalesce({})", attempts.join(", "))
+}
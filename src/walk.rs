@@ -0,0 +1,182 @@
+use glob::Pattern;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::{DirEntry, WalkDir};
+
+use crate::logging;
+
+/// File of glob patterns, one per line, of files and folders not to walk in its folder
+const IGNORE_FILE: &str = ".opensightignore";
+
+/// Which files and folders the walker visits
+#[derive(Debug, Default)]
+pub struct WalkOptions {
+    /// Files must match one of these, if any
+    pub include: Vec<Pattern>,
+    /// Files and folders matching any of these are skipped
+    pub exclude: Vec<Pattern>,
+    pub max_depth: Option<usize>,
+    pub follow_symlinks: bool,
+    pub skip_hidden: bool,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+/// Glob patterns of `--include`/`--exclude`
+pub fn parse_patterns(patterns: &[String]) -> Result<Vec<Pattern>, glob::PatternError> {
+    patterns
+        .iter()
+        .map(|pattern| Pattern::new(pattern))
+        .collect()
+}
+
+/// Size in bytes, with an optional `K`, `M`, `G` or `T` suffix (powers of 1024)
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, ""),
+    };
+    let shift = match unit
+        .trim()
+        .to_ascii_uppercase()
+        .trim_end_matches(['B', 'I'])
+    {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("invalid size: {}", value)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size: {}", value))
+}
+
+/// Whether a pattern matches the name or the path below `base` of a file or folder
+fn matches(pattern: &Pattern, path: &Path, base: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| pattern.matches(&name.to_string_lossy()))
+        || path
+            .strip_prefix(base)
+            .is_ok_and(|relative| pattern.matches_path(relative))
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry.depth() > 0 && entry.file_name().to_string_lossy().starts_with('.')
+}
+
+/// Patterns of the ignore file of a folder, empty without one
+fn read_ignore_file(dir: &Path) -> Vec<Pattern> {
+    let Ok(contents) = fs::read_to_string(dir.join(IGNORE_FILE)) else {
+        return Vec::new();
+    };
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match Pattern::new(line.trim_end_matches('/')) {
+            Ok(pattern) => Some(pattern),
+            Err(err) => {
+                warn!(
+                    "Invalid pattern in {}: {}",
+                    logging::path(&dir.join(IGNORE_FILE)),
+                    err
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+impl WalkOptions {
+    /// Entries under `root` the options let through. Folders filtered out are not walked.
+    /// When following symbolic links, a folder already walked (through a link loop or another
+    /// link to it) is skipped.
+    pub fn walk<'a>(
+        &'a self,
+        root: &'a Path,
+    ) -> impl Iterator<Item = walkdir::Result<DirEntry>> + 'a {
+        let mut walker = WalkDir::new(root).follow_links(self.follow_symlinks);
+        if let Some(max_depth) = self.max_depth {
+            walker = walker.max_depth(max_depth);
+        }
+        let mut ignore_files: HashMap<PathBuf, Vec<Pattern>> = HashMap::new();
+        let mut walked_dirs: HashSet<PathBuf> = HashSet::new();
+        walker
+            .into_iter()
+            .filter_entry(move |entry| self.keep(entry, root, &mut ignore_files, &mut walked_dirs))
+    }
+
+    fn keep(
+        &self,
+        entry: &DirEntry,
+        root: &Path,
+        ignore_files: &mut HashMap<PathBuf, Vec<Pattern>>,
+        walked_dirs: &mut HashSet<PathBuf>,
+    ) -> bool {
+        if entry.depth() == 0 {
+            if self.follow_symlinks {
+                walked_dirs.extend(fs::canonicalize(entry.path()));
+            }
+            return true;
+        }
+        let path = entry.path();
+        if self.skip_hidden && is_hidden(entry) {
+            return false;
+        }
+        if self
+            .exclude
+            .iter()
+            .any(|pattern| matches(pattern, path, root))
+        {
+            return false;
+        }
+        // Ignore files of the folders from the root down to this entry
+        let ignored = path.ancestors().skip(1).take(entry.depth()).any(|dir| {
+            ignore_files
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| read_ignore_file(dir))
+                .iter()
+                .any(|pattern| matches(pattern, path, dir))
+        });
+        if ignored {
+            return false;
+        }
+
+        if entry.file_type().is_dir() {
+            if self.follow_symlinks {
+                if let Ok(dir) = fs::canonicalize(path) {
+                    if !walked_dirs.insert(dir) {
+                        warn!(
+                            "Skipping {}, already walked through another symbolic link",
+                            logging::path(path)
+                        );
+                        return false;
+                    }
+                }
+            }
+            return true;
+        }
+        if !self.include.is_empty() && !self.include.iter().any(|p| matches(p, path, root)) {
+            return false;
+        }
+        if self.min_size.is_some() || self.max_size.is_some() {
+            let Ok(size) = entry.metadata().map(|meta| meta.len()) else {
+                // Unreadable files are reported when read
+                return true;
+            };
+            if self.min_size.is_some_and(|min| size < min)
+                || self.max_size.is_some_and(|max| size > max)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}